@@ -39,8 +39,8 @@ pub use remote_mailbox::*;
 
 pub use ockam_core::async_trait::async_trait as async_worker;
 pub use ockam_core::{
-    Address, Any, Encoded, Error, Message, Result, Route, Routed, RouterMessage, TransportMessage,
-    Worker,
+    register_scheme, Address, Any, Encoded, Error, Message, Result, Route, RouteError, Routed,
+    RouterMessage, StopReason, TraceContext, TransportMessage, Worker,
 };
 
 pub use ockam_channel::{SecureChannel, SecureChannelListenerMessage, SecureChannelMessage};
@@ -85,7 +85,7 @@ impl<T: Message> Worker for RemoteMailbox<T> {
         }
         info!("RemoteMailbox route: {}", route);
         let address;
-        if let Some(a) = route.clone().recipient().to_string().strip_prefix("0#") {
+        if let Some(a) = route.recipient()?.to_string().strip_prefix("0#") {
             address = a.to_string();
         } else {
             return Err(OckamError::InvalidHubResponse.into());
@@ -114,6 +114,8 @@ impl<T: Message> Worker for RemoteMailbox<T> {
             onward_route: self.destination.clone(),
             return_route,
             payload,
+            message_type: T::message_type(),
+            trace: None,
         };
 
         ctx.forward_message(msg).await?;
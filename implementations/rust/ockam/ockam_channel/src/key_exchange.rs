@@ -43,9 +43,6 @@ pub(crate) struct KeyExchangeResponseMessage {
 }
 
 impl KeyExchangeResponseMessage {
-    pub fn req_id(&self) -> &Vec<u8> {
-        &self.req_id
-    }
     pub fn payload(&self) -> &Option<Vec<u8>> {
         &self.payload
     }
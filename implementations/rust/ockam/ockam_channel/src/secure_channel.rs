@@ -12,8 +12,15 @@ use ockam_vault_core::{Secret, SymmetricVault};
 use rand::random;
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tracing::debug;
 
+/// How long to wait for the local key exchange worker to respond
+const KEY_EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The metric counting completed and failed handshakes
+pub const HANDSHAKES: &str = "ockam_secure_channel_handshakes_total";
+
 struct ChannelKeys {
     encrypt_key: Secret,
     decrypt_key: Secret,
@@ -135,6 +142,32 @@ impl SecureChannel {
         }
     }
 
+    /// Run one step of the key exchange, counting failed handshakes
+    async fn key_exchange_step(
+        &mut self,
+        ctx: &mut <Self as Worker>::Context,
+        route: Route,
+        request: KeyExchangeRequestMessage,
+        is_first_initiator_msg: bool,
+    ) -> Result<()> {
+        let res = match ctx
+            .request::<_, KeyExchangeResponseMessage, _>(route, request, KEY_EXCHANGE_TIMEOUT)
+            .await
+        {
+            Ok(m) => {
+                self.handle_key_exchange_local(ctx, m.take(), is_first_initiator_msg)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+
+        if res.is_err() {
+            ctx.metrics()
+                .increment(HANDSHAKES, &[("result", "failed")], 1);
+        }
+        res
+    }
+
     async fn handle_key_exchange_local(
         &mut self,
         ctx: &mut <Self as Worker>::Context,
@@ -179,6 +212,8 @@ impl SecureChannel {
                 decrypt_key: Secret::new(keys.decrypt_key()),
                 nonce: 1,
             });
+            ctx.metrics()
+                .increment(HANDSHAKES, &[("result", "completed")], 1);
 
             // Notify interested worker about finished key exchange
             if let Some(r) = self.key_exchange_completed_callback_route.take() {
@@ -260,21 +295,13 @@ impl Worker for SecureChannel {
             let req_id = b"CHANNEL_REQ".to_vec();
 
             // Kick in initiator to start key exchange process
-            ctx.send_message(
+            self.key_exchange_step(
+                ctx,
                 key_exchange_route,
-                KeyExchangeRequestMessage::InitiatorFirstMessage {
-                    req_id: req_id.clone(),
-                },
+                KeyExchangeRequestMessage::InitiatorFirstMessage { req_id },
+                true,
             )
             .await?;
-
-            let m = ctx
-                .receive_match(|m: &KeyExchangeResponseMessage| m.req_id() == &req_id)
-                .await?
-                .take()
-                .take();
-
-            self.handle_key_exchange_local(ctx, m, true).await?;
         } else {
             let responder = key_exchanger.responder();
             let responder = XResponder::new(responder);
@@ -308,22 +335,13 @@ impl Worker for SecureChannel {
 
                 // FIXME: Remove req_id in the future when we fix message without length decode
                 let req_id = b"CHANNEL_REQ".to_vec();
-                ctx.send_message(
+                self.key_exchange_step(
+                    ctx,
                     key_exchange_route,
-                    KeyExchangeRequestMessage::Payload {
-                        req_id: req_id.clone(),
-                        payload,
-                    },
+                    KeyExchangeRequestMessage::Payload { req_id, payload },
+                    false,
                 )
                 .await?;
-
-                let m = ctx
-                    .receive_match(|m: &KeyExchangeResponseMessage| m.req_id() == &req_id)
-                    .await?
-                    .take()
-                    .take();
-
-                self.handle_key_exchange_local(ctx, m, false).await?;
             }
             SecureChannelMessage::Encrypt { m } => {
                 debug!("SecureChannel received Encrypt");
@@ -335,6 +353,8 @@ impl Worker for SecureChannel {
                     onward_route,
                     return_route: reply,
                     payload: m,
+                    message_type: None,
+                    trace: None,
                 };
                 let payload = msg.encode()?;
 
@@ -56,6 +56,8 @@ impl Worker for SecureChannelListener {
                     onward_route: address.into(),
                     return_route: reply,
                     payload,
+                    message_type: SecureChannelMessage::message_type(),
+                    trace: None,
                 };
 
                 ctx.forward_message(msg).await?;
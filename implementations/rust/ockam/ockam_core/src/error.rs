@@ -48,7 +48,7 @@ impl Error {
     #[cfg(feature = "std")]
     #[inline]
     pub fn domain(&self) -> &'static str {
-        &self.domain
+        self.domain
     }
 
     /// Returns an error's code.
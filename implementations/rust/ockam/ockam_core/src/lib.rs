@@ -37,6 +37,7 @@ pub extern crate async_trait;
 
 mod error;
 mod message;
+mod message_type;
 mod routing;
 mod worker;
 
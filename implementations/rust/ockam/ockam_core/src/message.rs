@@ -1,7 +1,7 @@
 use crate::{
     lib::{
         fmt::{self, Debug, Display, Formatter},
-        Deref, DerefMut, Vec,
+        Deref, DerefMut, String, Vec,
     },
     Address, Result, Route, TransportMessage,
 };
@@ -18,6 +18,22 @@ pub trait Message: Sized + Send + 'static {
     /// Decode an [`Encoded`] type into the Message's type.
     #[allow(clippy::ptr_arg)]
     fn decode(e: &Encoded) -> Result<Self>;
+
+    /// Return the type identifier of this message type
+    ///
+    /// The identifier is sent alongside the encoded message, which
+    /// allows receivers to reject payloads of the wrong type instead
+    /// of trying to decode them.  Returning `None` means that the
+    /// message type is unknown, and disables this check.
+    ///
+    /// The identifier is part of the wire format, so it must not
+    /// change between builds or versions of a program.  Message types
+    /// that implement serde's traits are identified by their serde
+    /// name, which is the type name without its module path, unless
+    /// it is set with `#[serde(rename = "...")]`.
+    fn message_type() -> Option<String> {
+        None
+    }
 }
 
 // Auto-implement message trait for types that _can_ be messages
@@ -32,6 +48,12 @@ where
     fn decode(e: &Encoded) -> Result<Self> {
         Ok(serde_bare::from_slice(e.as_slice())?)
     }
+
+    /// Use the name serde knows the type by, which can be set with
+    /// `#[serde(rename = "...")]`
+    fn message_type() -> Option<String> {
+        crate::message_type::message_type::<T>().map(String::from)
+    }
 }
 
 // TODO: see comment in Cargo.toml about this dependency
@@ -82,9 +104,11 @@ impl<M: Message> Routed<M> {
         self.transport.return_route.clone()
     }
     /// Get a copy of the message sender address
+    ///
+    /// Returns an error if the message has an empty return route.
     #[inline]
-    pub fn sender(&self) -> Address {
-        self.transport.return_route.recipient()
+    pub fn sender(&self) -> Result<Address> {
+        Ok(self.transport.return_route.recipient()?)
     }
 
     /// Consume the message wrapper
@@ -99,6 +123,12 @@ impl<M: Message> Routed<M> {
         self.transport
     }
 
+    /// Return the type identifier the message was sent with, if any
+    #[inline]
+    pub fn message_type(&self) -> Option<&str> {
+        self.transport.message_type.as_deref()
+    }
+
     /// Get a reference to the underlying binary message payload
     #[inline]
     pub fn payload(&self) -> &Vec<u8> {
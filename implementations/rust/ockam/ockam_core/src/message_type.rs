@@ -0,0 +1,187 @@
+//! Stable type identifiers for serde message types
+//!
+//! A message type is identified by the name serde knows it by, which
+//! is the name of the struct or enum, unless it is renamed with
+//! `#[serde(rename = "...")]`.  Unlike Rust type names, this name
+//! doesn't depend on the module path or the compiler version, and can
+//! be chosen by the author of the message type.  Types without a name
+//! are identified by their serde data model type, such as `u64` or
+//! `string`.
+//!
+//! The name is found by deserializing the type from a [`Probe`],
+//! which fails as soon as the type tells it what it expects.
+
+use crate::lib::fmt::{self, Display, Formatter};
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+
+/// Return the type identifier of a message type, if it has one
+///
+/// Types that can deserialize from any input, such as self-describing
+/// values, have no identifier.
+pub(crate) fn message_type<T: DeserializeOwned>() -> Option<&'static str> {
+    match T::deserialize(Probe) {
+        Err(Found(name)) => name,
+        Ok(_) => None,
+    }
+}
+
+/// A deserializer that only records what it is asked for
+struct Probe;
+
+/// The "error" a [`Probe`] stops with
+#[derive(Debug)]
+struct Found(Option<&'static str>);
+
+impl Display for Found {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Some(name) => write!(f, "found message type '{}'", name),
+            None => write!(f, "message type is unknown"),
+        }
+    }
+}
+
+impl de::StdError for Found {}
+
+impl de::Error for Found {
+    fn custom<T: Display>(_: T) -> Self {
+        Found(None)
+    }
+}
+
+macro_rules! found {
+    ($($method:ident => $name:expr,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Found> {
+                Err(Found($name))
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Probe {
+    type Error = Found;
+
+    found! {
+        deserialize_any => None,
+        deserialize_ignored_any => None,
+        deserialize_identifier => None,
+        deserialize_bool => Some("bool"),
+        deserialize_i8 => Some("i8"),
+        deserialize_i16 => Some("i16"),
+        deserialize_i32 => Some("i32"),
+        deserialize_i64 => Some("i64"),
+        deserialize_u8 => Some("u8"),
+        deserialize_u16 => Some("u16"),
+        deserialize_u32 => Some("u32"),
+        deserialize_u64 => Some("u64"),
+        deserialize_f32 => Some("f32"),
+        deserialize_f64 => Some("f64"),
+        deserialize_char => Some("char"),
+        deserialize_str => Some("string"),
+        deserialize_string => Some("string"),
+        deserialize_bytes => Some("bytes"),
+        deserialize_byte_buf => Some("bytes"),
+        deserialize_option => Some("option"),
+        deserialize_unit => Some("unit"),
+        deserialize_seq => Some("seq"),
+        deserialize_map => Some("map"),
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: V,
+    ) -> Result<V::Value, Found> {
+        Err(Found(Some(name)))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: V,
+    ) -> Result<V::Value, Found> {
+        Err(Found(Some(name)))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, _: V) -> Result<V::Value, Found> {
+        Err(Found(Some("tuple")))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: usize,
+        _: V,
+    ) -> Result<V::Value, Found> {
+        Err(Found(Some(name)))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Found> {
+        Err(Found(Some(name)))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Found> {
+        Err(Found(Some(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::{String, Vec};
+    use serde::Deserialize;
+
+    mod a {
+        #[derive(serde::Deserialize)]
+        pub struct Ping;
+    }
+
+    mod b {
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "b.Ping")]
+        pub struct Ping;
+    }
+
+    #[derive(Deserialize)]
+    struct Named {
+        _n: u64,
+    }
+
+    #[derive(Deserialize)]
+    enum Command {
+        _Stop,
+    }
+
+    #[derive(Deserialize)]
+    struct Wrapper(u64);
+
+    #[test]
+    fn named_types() {
+        assert_eq!(message_type::<a::Ping>(), Some("Ping"));
+        assert_eq!(message_type::<b::Ping>(), Some("b.Ping"));
+        assert_eq!(message_type::<Named>(), Some("Named"));
+        assert_eq!(message_type::<Command>(), Some("Command"));
+        assert_eq!(message_type::<Wrapper>(), Some("Wrapper"));
+    }
+
+    #[test]
+    fn unnamed_types() {
+        assert_eq!(message_type::<u64>(), Some("u64"));
+        assert_eq!(message_type::<String>(), Some("string"));
+        assert_eq!(message_type::<Vec<u8>>(), Some("seq"));
+        assert_eq!(message_type::<Option<u8>>(), Some("option"));
+        assert_eq!(message_type::<(u8, u8)>(), Some("tuple"));
+        assert_eq!(message_type::<()>(), Some("unit"));
+    }
+}
@@ -1,7 +1,10 @@
-use crate::lib::{
-    fmt::{self, Display},
-    str::from_utf8,
-    String, ToString, Vec,
+use crate::{
+    lib::{
+        fmt::{self, Display},
+        str::{from_utf8, FromStr},
+        String, ToString, Vec,
+    },
+    RouteError,
 };
 use core::ops::Deref;
 use rand::distributions::Standard;
@@ -67,6 +70,27 @@ impl<'a> From<&'a str> for AddressSet {
 /// string, the first `#` symbol is used to separate the type from the
 /// rest of the address.  If no `#` symbol is found, the address is
 /// assumed to be of `tt = 0` (local worker).
+///
+/// Addresses can also start with the prefix of a registered scheme,
+/// such as `tcp://127.0.0.1:4000` or `local/echoer`.  See
+/// [`register_scheme`](crate::register_scheme).
+///
+/// Converting a string with `From` panics if the string is not a
+/// valid address.  Use [`str::parse`] for addresses that aren't known
+/// to be valid, for example because they are read from a
+/// configuration file.
+///
+/// ```
+/// # use ockam_core::{Address, RouteError};
+/// let addr: Address = "1#127.0.0.1:4000".parse().unwrap();
+/// assert_eq!(addr.tt, 1);
+///
+/// ockam_core::register_scheme("tcp://", 1).unwrap();
+/// assert_eq!(addr, "tcp://127.0.0.1:4000".parse().unwrap());
+///
+/// let err = "1#invalid#".parse::<Address>().unwrap_err();
+/// assert_eq!(err, RouteError::TooManySeparators);
+/// ```
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub struct Address {
     /// The address type
@@ -78,9 +102,55 @@ impl Address {
     /// Parse an address from a string
     ///
     /// See type documentation for more detail
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string is not a valid address.
     pub fn from_string<S: Into<String>>(s: S) -> Self {
         let buf: String = s.into();
-        let mut vec: Vec<_> = buf.split("#").collect();
+        match buf.parse() {
+            Ok(addr) => addr,
+            Err(e) => panic!("Invalid address string '{}': {:?}", buf, e),
+        }
+    }
+
+    /// Format this address with the scheme registered for its type
+    ///
+    /// Addresses of types without a scheme use the `tt#address`
+    /// format, like their `Display` implementation.
+    #[cfg(feature = "std")]
+    pub fn to_scheme_string(&self) -> String {
+        match super::scheme_prefix(self.tt) {
+            Some(prefix) => format!("{}{}", prefix, String::from_utf8_lossy(&self.inner)),
+            None => self.to_string(),
+        }
+    }
+
+    /// Format this address with the scheme registered for its type
+    #[cfg(not(feature = "std"))]
+    pub fn to_scheme_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl FromStr for Address {
+    type Err = RouteError;
+
+    fn from_str(s: &str) -> Result<Self, RouteError> {
+        #[cfg(feature = "std")]
+        {
+            if let Some((tt, inner)) = super::scheme::split_scheme(s)? {
+                if inner.is_empty() {
+                    return Err(RouteError::EmptyAddress);
+                }
+                return Ok(Self {
+                    tt,
+                    inner: inner.as_bytes().to_vec(),
+                });
+            }
+        }
+
+        let mut vec: Vec<_> = s.split('#').collect();
 
         // If after the split we only have one element, there was no
         // `#` separator, so the type needs to be implicitly `= 0`
@@ -90,25 +160,23 @@ impl Address {
         // If after the split we have 2 elements, we extract the type
         // value from the string, and use the rest as the address
         else if vec.len() == 2 {
-            let tt = match str::parse(vec.remove(0)) {
-                Ok(tt) => tt,
-                Err(e) => {
-                    panic!("Failed to parse address type: '{}'", e);
-                }
-            };
+            let tt = vec
+                .remove(0)
+                .parse()
+                .map_err(|_| RouteError::InvalidAddressType)?;
 
             (tt, vec.remove(0).as_bytes().to_vec())
         } else {
-            panic!("Invalid address string: more than one `#` separator found");
+            return Err(RouteError::TooManySeparators);
         };
 
-        Self { tt, inner }
+        Ok(Self { tt, inner })
     }
 }
 
 impl Display for Address {
     fn fmt<'a>(&'a self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &'a str = from_utf8(&self.inner.as_slice()).unwrap_or("Invalid UTF-8");
+        let inner: &'a str = from_utf8(self.inner.as_slice()).unwrap_or("Invalid UTF-8");
         write!(f, "{}#{}", self.tt, inner)
     }
 }
@@ -204,3 +272,36 @@ fn parse_addr_with_type() {
 fn parse_addr_invalid() {
     let _ = Address::from_string("1#invalid#");
 }
+
+#[test]
+fn parse_addr_errors() {
+    assert_eq!(
+        "1#invalid#".parse::<Address>(),
+        Err(RouteError::TooManySeparators)
+    );
+    assert_eq!(
+        "256#remote_friend".parse::<Address>(),
+        Err(RouteError::InvalidAddressType)
+    );
+    assert_eq!(
+        "tcp#remote_friend".parse::<Address>(),
+        Err(RouteError::InvalidAddressType)
+    );
+}
+
+#[test]
+fn parse_addr_with_scheme() {
+    crate::register_scheme("tcp://", 1).unwrap();
+    let addr: Address = "tcp://127.0.0.1:4000".parse().unwrap();
+    assert_eq!(addr, Address::from_string("1#127.0.0.1:4000"));
+    assert_eq!(addr.to_scheme_string(), "tcp://127.0.0.1:4000");
+
+    let addr: Address = "local/echoer".parse().unwrap();
+    assert_eq!(addr, Address::from_string("echoer"));
+
+    assert_eq!(
+        "carrier-pigeon://coop".parse::<Address>(),
+        Err(RouteError::UnknownScheme)
+    );
+    assert_eq!("tcp://".parse::<Address>(), Err(RouteError::EmptyAddress));
+}
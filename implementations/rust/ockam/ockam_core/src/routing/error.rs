@@ -0,0 +1,44 @@
+use crate::Error;
+
+/// Errors that can occur when parsing or resolving routes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteError {
+    /// No error
+    None,
+    /// The address type is not a number between 0 and 255
+    InvalidAddressType,
+    /// An address contains more than one `#` separator
+    TooManySeparators,
+    /// A route contains an empty address
+    EmptyAddress,
+    /// A route does not contain any address
+    EmptyRoute,
+    /// An address uses a scheme that was never registered
+    UnknownScheme,
+    /// A scheme prefix contains reserved characters
+    InvalidScheme,
+    /// The scheme or address type is already registered
+    SchemeExists,
+}
+
+impl RouteError {
+    /// Integer code associated with the error domain.
+    pub const DOMAIN_CODE: u32 = 17_000;
+    /// Descriptive name for the error domain.
+    pub const DOMAIN_NAME: &'static str = "OCKAM_ROUTING";
+}
+
+impl From<RouteError> for Error {
+    #[cfg(feature = "std")]
+    fn from(e: RouteError) -> Error {
+        Error::new(
+            RouteError::DOMAIN_CODE + (e as u32),
+            RouteError::DOMAIN_NAME,
+        )
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn from(e: RouteError) -> Error {
+        Error::new(RouteError::DOMAIN_CODE + (e as u32))
+    }
+}
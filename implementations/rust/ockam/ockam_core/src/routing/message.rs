@@ -1,5 +1,12 @@
-use crate::{lib::Vec, Address, Route};
-use serde::{Deserialize, Serialize};
+use crate::{
+    lib::{fmt, Display, String, Vec},
+    Address, Route,
+};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
 /// A generic transport message
 ///
@@ -7,9 +14,16 @@ use serde::{Deserialize, Serialize};
 /// crate) in order to provide a mechanism for third-party developers
 /// to create custom transport channel routers.  Casual users of ockam
 /// should never have to interact with this type directly.
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
+///
+/// Version 2 of the encoding adds the message type and the trace
+/// context.  Messages that carry neither are encoded as version 1, so
+/// that peers that only know version 1 can still decode them.
+#[derive(Debug, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub struct TransportMessage {
     /// The transport protocol version
+    ///
+    /// Messages with a message type or trace context are always sent
+    /// as version 2 or later.
     pub version: u8,
     /// Onward message route
     pub onward_route: Route,
@@ -20,6 +34,16 @@ pub struct TransportMessage {
     pub return_route: Route,
     /// The message payload
     pub payload: Vec<u8>,
+    /// The type identifier of the payload
+    ///
+    /// See [`Message::message_type`](crate::Message::message_type).
+    pub message_type: Option<String>,
+    /// The trace this message is part of
+    ///
+    /// Nodes propagate the trace context from every message a worker
+    /// handles to the messages it sends in turn, which allows
+    /// correlating the log output of all hops.
+    pub trace: Option<TraceContext>,
 }
 
 impl TransportMessage {
@@ -30,10 +54,124 @@ impl TransportMessage {
             onward_route,
             return_route: Route::new().into(),
             payload,
+            message_type: None,
+            trace: None,
         }
     }
 }
 
+impl TransportMessage {
+    /// The oldest version that carries message types and traces
+    const TYPED_VERSION: u8 = 2;
+
+    /// Return the version this message is encoded with
+    fn encoded_version(&self) -> u8 {
+        if self.message_type.is_some() || self.trace.is_some() {
+            self.version.max(Self::TYPED_VERSION)
+        } else {
+            self.version
+        }
+    }
+}
+
+impl Serialize for TransportMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let version = self.encoded_version();
+        let typed = version >= Self::TYPED_VERSION;
+
+        let mut msg = serializer.serialize_tuple(if typed { 6 } else { 4 })?;
+        msg.serialize_element(&version)?;
+        msg.serialize_element(&self.onward_route)?;
+        msg.serialize_element(&self.return_route)?;
+        msg.serialize_element(&self.payload)?;
+        if typed {
+            msg.serialize_element(&self.message_type)?;
+            msg.serialize_element(&self.trace)?;
+        }
+        msg.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for TransportMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MessageVisitor;
+
+        impl<'de> Visitor<'de> for MessageVisitor {
+            type Value = TransportMessage;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a transport message")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let version: u8 = next(&mut seq, 0)?;
+                let onward_route = next(&mut seq, 1)?;
+                let return_route = next(&mut seq, 2)?;
+                let payload = next(&mut seq, 3)?;
+
+                // Version 1 messages end after the payload
+                let (message_type, trace) = if version >= TransportMessage::TYPED_VERSION {
+                    (next(&mut seq, 4)?, next(&mut seq, 5)?)
+                } else {
+                    (None, None)
+                };
+
+                Ok(TransportMessage {
+                    version,
+                    onward_route,
+                    return_route,
+                    payload,
+                    message_type,
+                    trace,
+                })
+            }
+        }
+
+        fn next<'de, A, T>(seq: &mut A, index: usize) -> Result<T, A::Error>
+        where
+            A: SeqAccess<'de>,
+            T: Deserialize<'de>,
+        {
+            seq.next_element()?
+                .ok_or_else(|| de::Error::invalid_length(index, &MessageVisitor))
+        }
+
+        // Version 1 messages only have 4 fields, which is fine since
+        // the number of fields is never encoded
+        deserializer.deserialize_tuple(6, MessageVisitor)
+    }
+}
+
+/// Correlates all hops of a message across workers and nodes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct TraceContext {
+    /// The identifier shared by all hops of a trace
+    pub trace_id: u64,
+    /// The number of hops since the trace was started
+    pub hop: u32,
+}
+
+impl TraceContext {
+    /// Start a new trace
+    pub fn new(trace_id: u64) -> Self {
+        Self { trace_id, hop: 0 }
+    }
+
+    /// Return the trace context for the next hop of a message
+    pub fn next_hop(self) -> Self {
+        Self {
+            hop: self.hop.saturating_add(1),
+            ..self
+        }
+    }
+}
+
+impl Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}/{}", self.trace_id, self.hop)
+    }
+}
+
 /// A command message for router implementations
 ///
 /// If a router is implemented as a worker, it should accept this
@@ -49,4 +187,78 @@ pub enum RouterMessage {
         /// The clients own worker bus address
         self_addr: Address,
     },
+    /// Remove a client from this routing scope
+    ///
+    /// The client is only removed if it's still the one registered
+    /// for the accept scope.
+    Deregister {
+        /// The accept scope the client was registered for
+        accepts: Address,
+        /// The clients own worker bus address
+        self_addr: Address,
+    },
+}
+
+#[test]
+fn trace_context_next_hop() {
+    let trace = TraceContext::new(0xbeef).next_hop().next_hop();
+    assert_eq!(trace.trace_id, 0xbeef);
+    assert_eq!(trace.hop, 2);
+    assert_eq!(format!("{}", trace), "000000000000beef/2");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The encoding of version 1, which predates message types
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct V1 {
+        version: u8,
+        onward_route: Route,
+        return_route: Route,
+        payload: Vec<u8>,
+    }
+
+    fn v1() -> V1 {
+        V1 {
+            version: 1,
+            onward_route: Route::new().append("app").into(),
+            return_route: Route::new().append("1#127.0.0.1:4000").into(),
+            payload: vec![1, 2, 3],
+        }
+    }
+
+    fn message() -> TransportMessage {
+        let mut msg = TransportMessage::v1(Route::new().append("app").into(), vec![1, 2, 3]);
+        msg.return_route = Route::new().append("1#127.0.0.1:4000").into();
+        msg
+    }
+
+    #[test]
+    fn untyped_messages_keep_version_1() {
+        let buf = serde_bare::to_vec(&message()).unwrap();
+        assert_eq!(buf, serde_bare::to_vec(&v1()).unwrap());
+        assert_eq!(serde_bare::from_slice::<V1>(&buf).unwrap(), v1());
+
+        let msg: TransportMessage = serde_bare::from_slice(&buf).unwrap();
+        assert_eq!(msg, message());
+    }
+
+    #[test]
+    fn typed_messages_use_version_2() {
+        let mut msg = message();
+        msg.message_type = Some("Ping".into());
+        msg.trace = Some(TraceContext::new(7));
+
+        let buf = serde_bare::to_vec(&msg).unwrap();
+        assert_eq!(buf[0], 2);
+
+        let decoded: TransportMessage = serde_bare::from_slice(&buf).unwrap();
+        assert_eq!(decoded, TransportMessage { version: 2, ..msg });
+
+        // Version 2 messages must have all fields
+        let res = serde_bare::from_slice::<TransportMessage>(&buf[..buf.len() - 1]);
+        assert!(res.is_err());
+    }
 }
@@ -3,6 +3,14 @@
 mod address;
 pub use address::*;
 
+mod error;
+pub use error::*;
+
+#[cfg(feature = "std")]
+mod scheme;
+#[cfg(feature = "std")]
+pub use scheme::{register_scheme, scheme_prefix};
+
 mod route;
 pub use route::*;
 
@@ -1,9 +1,10 @@
 use crate::{
     lib::{
         fmt::{self, Display},
+        str::FromStr,
         String, Vec, VecDeque,
     },
-    Address,
+    Address, RouteError,
 };
 use serde::{Deserialize, Serialize};
 
@@ -15,30 +16,18 @@ pub struct Route {
 
 impl Route {
     /// Create an empty RouteBuilder
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> RouteBuilder<'static> {
         RouteBuilder::new()
     }
 
     /// Parse a route from a string
-    pub fn parse<S: Into<String>>(s: S) -> Option<Route> {
-        let s = s.into();
-        if s == "" {
-            return None;
-        }
-
-        let addrs = s.split("=>").collect::<Vec<_>>();
-
-        // Invalid route
-        if addrs.len() == 0 {
-            return None;
-        }
-
-        Some(
-            addrs
-                .into_iter()
-                .fold(Route::new(), |r, addr| r.append(addr.trim()))
-                .into(),
-        )
+    ///
+    /// Routes are written as a list of addresses separated by `=>`,
+    /// for example `1#127.0.0.1:4000 => echoer`, or with registered
+    /// schemes `tcp://127.0.0.1:4000 => local/echoer`.
+    pub fn parse<S: Into<String>>(s: S) -> Result<Route, RouteError> {
+        s.into().parse()
     }
 
     /// Create a new [`RouteBuilder`] from the current Route
@@ -62,14 +51,36 @@ impl Route {
     }
 
     /// Get the final recipient address
-    pub fn recipient(&self) -> Address {
-        self.inner
-            .back()
-            .cloned()
-            .expect("Route::recipient failed on invalid Route!")
+    ///
+    /// Returns an error if the route is empty.
+    pub fn recipient(&self) -> Result<Address, RouteError> {
+        self.inner.back().cloned().ok_or(RouteError::EmptyRoute)
     }
 }
 
+impl FromStr for Route {
+    type Err = RouteError;
+
+    fn from_str(s: &str) -> Result<Self, RouteError> {
+        if s.trim().is_empty() {
+            return Err(RouteError::EmptyRoute);
+        }
+
+        let inner = s
+            .split("=>")
+            .map(|addr| match addr.trim() {
+                "" => Err(RouteError::EmptyAddress),
+                addr => addr.parse(),
+            })
+            .collect::<Result<VecDeque<_>, RouteError>>()?;
+
+        Ok(Self { inner })
+    }
+}
+
+// Routes are displayed with registered schemes, including `local/`
+// for local addresses, so that the output can be parsed into the
+// same route again
 impl Display for Route {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -77,7 +88,7 @@ impl Display for Route {
             "{}",
             self.inner
                 .iter()
-                .map(|a| format!("{}", a))
+                .map(|a| a.to_scheme_string())
                 .collect::<Vec<_>>()
                 .join(" => ")
         )
@@ -154,3 +165,45 @@ impl Drop for RouteBuilder<'_> {
         }
     }
 }
+
+#[test]
+fn parse_route() {
+    let route = Route::parse("1#127.0.0.1:4000 => echoer").unwrap();
+    assert_eq!(
+        route.next(),
+        Some(&Address::from_string("1#127.0.0.1:4000"))
+    );
+    assert_eq!(route.recipient(), Ok(Address::from_string("echoer")));
+}
+
+#[test]
+fn parse_route_errors() {
+    assert_eq!(Route::parse(" "), Err(RouteError::EmptyRoute));
+    assert_eq!(Route::parse("a => => b"), Err(RouteError::EmptyAddress));
+    assert_eq!(
+        Route::parse("a => x#b"),
+        Err(RouteError::InvalidAddressType)
+    );
+
+    let empty: Route = Route::new().into();
+    assert_eq!(empty.recipient(), Err(RouteError::EmptyRoute));
+}
+
+#[test]
+fn route_display_round_trip() {
+    crate::register_scheme("tcp://", 1).unwrap();
+    let route = Route::parse("1#127.0.0.1:4000 => echoer").unwrap();
+    assert_eq!(format!("{}", route), "tcp://127.0.0.1:4000 => local/echoer");
+
+    let routes = [
+        "echoer",
+        "0#echoer => 1#127.0.0.1:4000 => 0#app",
+        "local/a#b => tcp://[::1]:4000",
+        // Types without a scheme keep the `tt#address` format
+        "9#peer => 0#app",
+    ];
+    for s in routes.iter() {
+        let route = Route::parse(*s).unwrap();
+        assert_eq!(Route::parse(format!("{}", route)), Ok(route));
+    }
+}
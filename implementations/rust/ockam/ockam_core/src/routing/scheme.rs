@@ -0,0 +1,113 @@
+//! Named address schemes
+//!
+//! Addresses are written as `tt#address` by default, which is hard to
+//! read for anything but local workers.  A scheme gives an address
+//! type a readable prefix, so that `1#127.0.0.1:4000` can be written
+//! as `tcp://127.0.0.1:4000`.  Schemes are used when parsing
+//! addresses and routes, and when displaying routes, which means that
+//! a route can be copied from log output into a configuration file.
+//!
+//! The `local/` scheme is always registered.  Transports register
+//! schemes for their own address types with [`register_scheme`], such
+//! as `tcp://` for TCP peers.
+
+use crate::{
+    lib::{BTreeMap, String, ToString},
+    RouteError,
+};
+use std::sync::RwLock;
+
+lazy_static::lazy_static! {
+    /// The scheme prefix of each address type
+    static ref SCHEMES: RwLock<BTreeMap<u8, String>> = {
+        let mut schemes = BTreeMap::new();
+        schemes.insert(0, "local/".to_string());
+        RwLock::new(schemes)
+    };
+}
+
+/// Register a readable prefix for an address type
+///
+/// Each address type can have a single prefix, and each prefix can
+/// only be used by a single address type.  Registering the same
+/// prefix for the same type again does nothing.  A prefix must not be
+/// empty, and must not contain whitespace, `#` or `=>`.
+pub fn register_scheme<S: Into<String>>(prefix: S, tt: u8) -> Result<(), RouteError> {
+    let prefix = prefix.into();
+    if prefix.is_empty()
+        || prefix.contains('#')
+        || prefix.contains("=>")
+        || prefix.contains(char::is_whitespace)
+    {
+        return Err(RouteError::InvalidScheme);
+    }
+
+    let mut schemes = SCHEMES.write().unwrap();
+    match schemes.get(&tt) {
+        Some(existing) if existing == &prefix => return Ok(()),
+        Some(_) => return Err(RouteError::SchemeExists),
+        None => {}
+    }
+    if schemes.values().any(|existing| existing == &prefix) {
+        return Err(RouteError::SchemeExists);
+    }
+
+    schemes.insert(tt, prefix);
+    Ok(())
+}
+
+/// Return the prefix registered for an address type
+pub fn scheme_prefix(tt: u8) -> Option<String> {
+    SCHEMES.read().unwrap().get(&tt).cloned()
+}
+
+/// Check whether a string looks like a URI scheme, such as `tcp`
+///
+/// Schemes start with a letter, followed by letters, digits, `+`, `-`
+/// or `.`.
+fn is_scheme_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+}
+
+/// Split the scheme prefix off an address string
+///
+/// Returns `None` if the string doesn't start with a registered
+/// prefix, in which case it uses the `tt#address` format.  Strings
+/// that start with what looks like an unregistered scheme, such as
+/// `udp://`, are rejected.
+pub(crate) fn split_scheme(s: &str) -> Result<Option<(u8, &str)>, RouteError> {
+    let schemes = SCHEMES.read().unwrap();
+    let found = schemes
+        .iter()
+        .filter(|(_, prefix)| s.starts_with(prefix.as_str()))
+        .max_by_key(|(_, prefix)| prefix.len());
+
+    match found {
+        Some((tt, prefix)) => Ok(Some((*tt, &s[prefix.len()..]))),
+        None => match s.find("://") {
+            Some(end) if is_scheme_name(&s[..end]) => Err(RouteError::UnknownScheme),
+            _ => Ok(None),
+        },
+    }
+}
+
+#[test]
+fn split_registered_schemes() {
+    register_scheme("test-scheme://", 200).unwrap();
+    assert_eq!(split_scheme("test-scheme://a"), Ok(Some((200, "a"))));
+    assert_eq!(split_scheme("local/echoer"), Ok(Some((0, "echoer"))));
+    assert_eq!(split_scheme("echoer"), Ok(None));
+}
+
+#[test]
+fn split_unknown_schemes() {
+    assert_eq!(split_scheme("udp+v2://a"), Err(RouteError::UnknownScheme));
+    assert_eq!(split_scheme("0#a://b"), Ok(None));
+    assert_eq!(split_scheme("my worker://a"), Ok(None));
+    assert_eq!(split_scheme("://a"), Ok(None));
+}
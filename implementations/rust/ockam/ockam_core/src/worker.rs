@@ -1,6 +1,19 @@
 use crate::{lib::Box, Message, Result, Routed};
 use async_trait::async_trait;
 
+/// The reason why a worker is being shut down
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The worker was explicitly stopped
+    Requested,
+    /// The node that the worker runs on is shutting down
+    NodeShutdown,
+    /// The worker failed while handling a message
+    Error,
+    /// The worker lost the connection to a remote peer
+    PeerLost,
+}
+
 /// Base ockam worker trait.
 #[async_trait]
 pub trait Worker: Send + 'static {
@@ -16,7 +29,11 @@ pub trait Worker: Send + 'static {
     }
 
     /// Override shutdown behaviour
-    fn shutdown(&mut self, _context: &mut Self::Context) -> Result<()> {
+    ///
+    /// This function is called after the worker has handled all
+    /// messages that were queued when it was stopped.  The `reason`
+    /// describes why the worker is being shut down.
+    async fn shutdown(&mut self, _context: &mut Self::Context, _reason: StopReason) -> Result<()> {
         Ok(())
     }
 
@@ -33,8 +33,8 @@ async fn main(ctx: Context) -> Result<()> {
     let mut buffer = String::new();
     println!("Paste the forwarding route below ↓");
     io::stdin().read_line(&mut buffer).unwrap();
-    let route = Route::parse(buffer).unwrap_or_else(|| {
-        error!("Failed to parse route!");
+    let route = Route::parse(buffer).unwrap_or_else(|e| {
+        error!("Failed to parse route: {:?}", e);
         eprintln!("Route format [type#]<address> [=> [type#]<address>]+");
         std::process::exit(1);
     });
@@ -13,7 +13,7 @@ impl Worker for Square {
 
     async fn handle_message(&mut self, ctx: &mut Context, msg: Routed<Num>) -> Result<()> {
         println!("Getting square request for number {}", msg.0);
-        ctx.send_message(msg.sender(), Num(msg.0 * msg.0)).await
+        ctx.send_message(msg.sender()?, Num(msg.0 * msg.0)).await
     }
 }
 
@@ -22,7 +22,7 @@ impl Worker for Printer {
         _context: &mut Context,
         msg: Routed<PrintMessage>,
     ) -> Result<()> {
-        println!("[{:?}]: {}", msg.sender(), msg.0);
+        println!("[{}]: {}", msg.sender()?, msg.0);
         Ok(())
     }
 }
@@ -60,6 +60,15 @@ impl Worker for Router {
                 );
                 self.routes.insert(accepts, self_addr);
             }
+            Deregister { accepts, self_addr } => {
+                info!(
+                    "Router deregister: `{}` address from worker `{}`",
+                    accepts, self_addr
+                );
+                if self.routes.get(&accepts) == Some(&self_addr) {
+                    self.routes.remove(&accepts);
+                }
+            }
         }
 
         Ok(())
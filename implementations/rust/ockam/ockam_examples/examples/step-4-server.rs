@@ -13,7 +13,7 @@ impl Worker for EchoService {
 
     async fn handle_message(&mut self, ctx: &mut Context, msg: Routed<String>) -> Result<()> {
         if &msg.as_str() == &"register" {
-            let address = msg.reply().recipient().to_string();
+            let address = msg.reply().recipient()?.to_string();
             println!(
                 "echo_service: My address on the hub is {}",
                 address.strip_prefix("0:").unwrap()
@@ -1,22 +1,37 @@
 use crate::{
-    block_future,
     error::Error,
+    multi::Multi,
     node::NullWorker,
     parser,
     relay::{self, RelayMessage},
-    Cancel, Mailbox, NodeMessage,
+    supervisor,
+    timer::{self, TimerMessage},
+    Cancel, Mailbox, MailboxOptions, MailboxSender, Metrics, MultiWorker, NodeInfo, NodeMessage,
+    RestartPolicy, TimerHandle,
+};
+use ockam_core::{
+    Address, AddressSet, Message, Result, Route, RouteError, Routed, StopReason, TraceContext,
+    TransportMessage, Worker,
+};
+use rand::distributions::{Distribution, Standard};
+use std::{
+    any::type_name,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use ockam_core::{Address, AddressSet, Message, Result, Route, TransportMessage, Worker};
-use std::sync::Arc;
 use tokio::{
     runtime::Runtime,
     sync::mpsc::{channel, Sender},
+    time,
 };
 
 pub struct Context {
     address: AddressSet,
     sender: Sender<NodeMessage>,
     rt: Arc<Runtime>,
+    metrics: Arc<Metrics>,
+    /// The trace of the message currently being handled
+    pub(crate) trace: Option<TraceContext>,
     pub(crate) mailbox: Mailbox,
 }
 
@@ -25,10 +40,99 @@ impl Drop for Context {
         let addr = self.address.first();
         trace!("Running Context::drop()");
 
-        if let Err(e) = block_future(self.rt.as_ref(), async { self.stop_worker(addr).await }) {
-            error!("Error occured during Context::drop(): {}", e);
-        };
+        // Tell the router that this worker is gone.  If the router
+        // is stopping this worker, this is the confirmation it waits
+        // for, otherwise it will deregister the worker right away.
+        //
+        // Dropping a context must not block, since it may happen on
+        // the only thread of the runtime
+        let ack = NodeMessage::StopAck(addr.clone());
+        let sender = self.sender.clone();
+        self.rt.spawn(async move {
+            if let Err(e) = sender.send(ack).await {
+                debug!("Failed to confirm shutdown of worker {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Draw a random value
+///
+/// Values are drawn from the generator of the running simulation, if
+/// any, so that trace ids and temporary addresses are the same every
+/// time a simulation is run with the same seed.
+fn random<T>() -> T
+where
+    Standard: Distribution<T>,
+{
+    #[cfg(feature = "sim")]
+    if let Some(value) = crate::sim::random() {
+        return value;
     }
+    rand::random()
+}
+
+/// Resolve the next hop of a route, and deliver an encoded message to it
+pub(crate) async fn deliver(
+    router: &Sender<NodeMessage>,
+    route: Route,
+    payload: Vec<u8>,
+    message_type: Option<String>,
+    trace: TraceContext,
+    return_addr: Address,
+) -> Result<()> {
+    let (reply_tx, mut reply_rx) = channel(1);
+    let next = route.next().ok_or(RouteError::EmptyRoute)?;
+    let req = NodeMessage::SenderReq(next.clone(), reply_tx);
+
+    // First resolve the next hop in the route
+    router.send(req).await.map_err(Error::from)?;
+    let (addr, sender, needs_wrapping) = reply_rx
+        .recv()
+        .await
+        .ok_or(Error::InternalIOFailure)??
+        .take_sender()?;
+
+    // Pack the payload into a TransportMessage
+    let mut data = TransportMessage::v1(route.clone(), payload);
+    data.return_route.modify().append(return_addr);
+    data.message_type = message_type;
+    data.trace = Some(trace);
+
+    // Pack transport message into relay message wrapper
+    let msg = if needs_wrapping {
+        RelayMessage::pre_router(addr, data, route)
+    } else {
+        RelayMessage::direct(addr, data, route)
+    };
+
+    // Send the packed user message with associated route
+    sender.send(msg).await?;
+
+    Ok(())
+}
+
+/// Forward a transport message to the next hop of its onward route
+pub(crate) async fn forward(router: &Sender<NodeMessage>, data: TransportMessage) -> Result<()> {
+    // Resolve the sender for the next hop in the messages route
+    let (reply_tx, mut reply_rx) = channel(1);
+    let next = data.onward_route.next().ok_or(RouteError::EmptyRoute)?;
+    let req = NodeMessage::SenderReq(next.clone(), reply_tx);
+
+    // First resolve the next hop in the route
+    router.send(req).await.map_err(Error::from)?;
+    let (addr, sender, _) = reply_rx
+        .recv()
+        .await
+        .ok_or(Error::InternalIOFailure)??
+        .take_sender()?;
+
+    // Pack the transport message into a relay message
+    let onward = data.onward_route.clone();
+    let msg = RelayMessage::direct(addr, data, onward);
+    sender.send(msg).await?;
+
+    Ok(())
 }
 
 impl Context {
@@ -37,27 +141,69 @@ impl Context {
         sender: Sender<NodeMessage>,
         address: AddressSet,
         mailbox: Mailbox,
+        metrics: Arc<Metrics>,
     ) -> Self {
         Self {
             rt,
             sender,
             address,
+            metrics,
+            trace: None,
             mailbox,
         }
     }
 
     /// Return the primary worker address
     pub fn primary_address(&self) -> Address {
-        self.address.first().clone()
+        self.address.first()
+    }
+
+    /// Return a handle to the router of this context's node
+    pub(crate) fn router(&self) -> Sender<NodeMessage> {
+        self.sender.clone()
+    }
+
+    /// Return the counters of this context's node
+    ///
+    /// Counters can be increased by any worker, and are exported by
+    /// a [`MetricsWorker`](crate::MetricsWorker).
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Return the trace of the message this worker is currently handling
+    pub fn trace(&self) -> Option<TraceContext> {
+        self.trace
+    }
+
+    /// Return the trace context for a message sent by this worker
+    ///
+    /// Messages sent while handling a traced message continue its
+    /// trace, all other messages start a new one.
+    fn outgoing_trace(&self) -> TraceContext {
+        match self.trace {
+            Some(trace) => trace.next_hop(),
+            None => TraceContext::new(random()),
+        }
+    }
+
+    /// Return the number of messages dropped because this worker's mailbox was full
+    pub fn dropped_messages(&self) -> usize {
+        self.mailbox.dropped()
     }
 
     /// Create a new context without spawning a full worker
     pub async fn new_context<S: Into<Address>>(&self, addr: S) -> Result<Context> {
         let addr = addr.into();
-        let ctx = NullWorker::new(Arc::clone(&self.rt), &addr, self.sender.clone());
+        let ctx = NullWorker::new(
+            Arc::clone(&self.rt),
+            &addr,
+            self.sender.clone(),
+            Arc::clone(&self.metrics),
+        );
 
         // Create a small relay and register it with the internal router
-        let sender = relay::build_root::<NullWorker, _>(Arc::clone(&self.rt), &ctx.mailbox);
+        let sender = relay::build_root(&ctx.mailbox);
         let (msg, mut rx) = NodeMessage::start_worker(addr.into(), sender);
         self.sender
             .send(msg)
@@ -77,6 +223,98 @@ impl Context {
         S: Into<AddressSet>,
         NM: Message + Send + 'static,
         NW: Worker<Context = Context, Message = NM>,
+    {
+        self.start_worker_with_mailbox(address, worker, MailboxOptions::default())
+            .await
+    }
+
+    /// Start a new worker with a custom mailbox configuration
+    ///
+    /// Use this function to change how many messages can be queued
+    /// for the worker, and what happens to new messages once its
+    /// mailbox is full.  See [`MailboxOptions`] for details.
+    ///
+    /// [`MailboxOptions`]: crate::MailboxOptions
+    pub async fn start_worker_with_mailbox<NM, NW, S>(
+        &self,
+        address: S,
+        worker: NW,
+        options: MailboxOptions,
+    ) -> Result<()>
+    where
+        S: Into<AddressSet>,
+        NM: Message + Send + 'static,
+        NW: Worker<Context = Context, Message = NM>,
+    {
+        let rt = self.rt.as_ref();
+        let mb = Mailbox::new(options, NM::message_type(), type_name::<NW>());
+        self.start_relay(address, mb, |ctx| relay::build::<NW, NM>(rt, worker, ctx))
+            .await
+    }
+
+    /// Start a new worker handling several message types
+    ///
+    /// Incoming messages are dispatched to the worker's [`Handler`]
+    /// implementations, based on which registered message type can
+    /// decode them.  Messages that no handler accepts are passed to
+    /// [`MultiWorker::fallback`] instead of being retried.
+    ///
+    /// [`Handler`]: crate::Handler
+    /// [`MultiWorker::fallback`]: crate::MultiWorker::fallback
+    pub async fn start_multi_worker<NW, S>(&self, address: S, worker: NW) -> Result<()>
+    where
+        S: Into<AddressSet>,
+        NW: MultiWorker,
+    {
+        self.start_worker(address, Multi::new(worker)).await
+    }
+
+    /// Start a new supervised worker at [`Address`](ockam_core::Address)
+    ///
+    /// Instead of a worker instance this function takes a factory,
+    /// which is used to create a fresh worker instance every time the
+    /// previous one failed and the [`RestartPolicy`] permits a
+    /// restart.  A worker fails when it panics, or returns an error
+    /// from either `initialize` or `handle_message`.
+    ///
+    /// Failures that are escalated will be reported to this context
+    /// via a [`WorkerFailed`] message.
+    ///
+    /// [`RestartPolicy`]: crate::RestartPolicy
+    /// [`WorkerFailed`]: crate::WorkerFailed
+    pub async fn start_supervised_worker<NM, NW, S, F>(
+        &self,
+        address: S,
+        factory: F,
+        policy: RestartPolicy,
+    ) -> Result<()>
+    where
+        S: Into<AddressSet>,
+        NM: Message + Send + 'static,
+        NW: Worker<Context = Context, Message = NM>,
+        F: Fn() -> NW + Send + 'static,
+    {
+        let rt = self.rt.as_ref();
+        let parent = self.primary_address();
+        let mb = Mailbox::new(
+            MailboxOptions::default(),
+            NM::message_type(),
+            type_name::<NW>(),
+        );
+        self.start_relay(address, mb, |ctx| {
+            supervisor::build::<NW, NM, F>(rt, factory, policy, parent, ctx)
+        })
+        .await
+    }
+
+    /// Register a new worker relay with the router
+    ///
+    /// The `build` closure is given the context of the new worker,
+    /// and has to spawn the relay that drives it.
+    async fn start_relay<S, B>(&self, address: S, mb: Mailbox, build: B) -> Result<()>
+    where
+        S: Into<AddressSet>,
+        B: FnOnce(Context) -> MailboxSender,
     {
         let address = address.into();
 
@@ -88,15 +326,17 @@ impl Context {
             .map_err(|_| Error::InternalIOFailure)?;
         check_rx.recv().await.ok_or(Error::InternalIOFailure)??;
 
-        // Build the mailbox first
-        let (mb_tx, mb_rx) = channel(32);
-        let mb = Mailbox::new(mb_rx, mb_tx);
-
-        // Pass it to the context
-        let ctx = Context::new(self.rt.clone(), self.sender.clone(), address.clone(), mb);
+        // Pass the mailbox to the context
+        let ctx = Context::new(
+            self.rt.clone(),
+            self.sender.clone(),
+            address.clone(),
+            mb,
+            Arc::clone(&self.metrics),
+        );
 
         // Then initialise the worker message relay
-        let sender = relay::build::<NW, NM>(self.rt.as_ref(), worker, ctx);
+        let sender = build(ctx);
 
         // Send start request to router
         let (msg, mut rx) = NodeMessage::start_worker(address, sender);
@@ -124,15 +364,41 @@ impl Context {
     }
 
     /// Shut down a worker by its primary address
+    ///
+    /// This function waits until the worker has handled all queued
+    /// messages and its shutdown handler has finished.  A worker can
+    /// also stop itself, in which case this function returns right
+    /// away, since the shutdown only starts once the current message
+    /// has been handled.
     pub async fn stop_worker<A: Into<Address>>(&self, addr: A) -> Result<()> {
+        self.stop_worker_with_reason(addr, StopReason::Requested)
+            .await
+    }
+
+    /// Shut down a worker, passing a [`StopReason`] to its shutdown handler
+    ///
+    /// See [`Context::stop_worker`] for details.
+    ///
+    /// [`StopReason`]: ockam_core::StopReason
+    /// [`Context::stop_worker`]: crate::Context::stop_worker
+    pub async fn stop_worker_with_reason<A: Into<Address>>(
+        &self,
+        addr: A,
+        reason: StopReason,
+    ) -> Result<()> {
         let addr = addr.into();
         debug!("Shutting down worker {}", addr);
 
         // Send the stop request
-        let (req, mut rx) = NodeMessage::stop_worker(addr);
-        self.sender.send(req).await.map_err(|e| Error::from(e))?;
+        let (req, mut rx) = NodeMessage::stop_worker(addr.clone(), reason);
+        self.sender.send(req).await.map_err(Error::from)?;
+
+        // Waiting for our own shutdown would never finish
+        if self.address.as_ref().contains(&addr) {
+            return Ok(());
+        }
 
-        // Then check that the worker was properly shut down
+        // Then wait for the worker to be properly shut down
         Ok(rx
             .recv()
             .await
@@ -181,35 +447,71 @@ impl Context {
             return Err(Error::SenderAddressDoesntExist.into());
         }
 
-        let route = route.into();
-        let (reply_tx, mut reply_rx) = channel(1);
-        let next = route.next().unwrap(); // TODO: communicate bad routes
-        let req = NodeMessage::SenderReq(next.clone(), reply_tx);
+        let payload = msg.encode()?;
+        deliver(
+            &self.sender,
+            route.into(),
+            payload,
+            M::message_type(),
+            self.outgoing_trace(),
+            self.primary_address(),
+        )
+        .await
+    }
 
-        // First resolve the next hop in the route
-        self.sender.send(req).await.map_err(|e| Error::from(e))?;
-        let (addr, sender, needs_wrapping) = reply_rx
-            .recv()
-            .await
-            .ok_or(Error::InternalIOFailure)??
-            .take_sender()?;
-
-        // Pack the payload into a TransportMessage
-        let payload = msg.encode().unwrap();
-        let mut data = TransportMessage::v1(route.clone(), payload);
-        data.return_route.modify().append(self.primary_address());
-
-        // Pack transport message into relay message wrapper
-        let msg = if needs_wrapping {
-            RelayMessage::pre_router(addr, data, route)
-        } else {
-            RelayMessage::direct(addr, data, route)
-        };
+    /// Send a message via a fully qualified route after `delay` has elapsed
+    ///
+    /// The message is delivered like it was sent with
+    /// [`Context::send_message`] at that time.  The timer is stopped
+    /// if this worker is stopped before the message was delivered.
+    ///
+    /// [`Context::send_message`]: crate::Context::send_message
+    pub fn send_after<R, M>(&self, route: R, msg: M, delay: Duration) -> Result<TimerHandle>
+    where
+        R: Into<Route>,
+        M: Message + Send + 'static,
+    {
+        self.start_timer(route.into(), msg, delay, None)
+    }
 
-        // Send the packed user message with associated route
-        sender.send(msg).await.map_err(|e| Error::from(e))?;
+    /// Send a message via a fully qualified route every `period`
+    ///
+    /// The first message is delivered after one `period` has
+    /// elapsed.  The timer runs until it is cancelled via its
+    /// [`TimerHandle`], or this worker is stopped.
+    ///
+    /// [`TimerHandle`]: crate::TimerHandle
+    pub fn interval<R, M>(&self, route: R, msg: M, period: Duration) -> Result<TimerHandle>
+    where
+        R: Into<Route>,
+        M: Message + Send + 'static,
+    {
+        self.start_timer(route.into(), msg, period, Some(period))
+    }
+
+    fn start_timer<M: Message>(
+        &self,
+        route: Route,
+        msg: M,
+        delay: Duration,
+        period: Option<Duration>,
+    ) -> Result<TimerHandle> {
+        let msg = TimerMessage {
+            route,
+            payload: msg.encode()?,
+            message_type: M::message_type(),
+            trace: self.outgoing_trace(),
+        };
 
-        Ok(())
+        Ok(timer::start(
+            self.rt.as_ref(),
+            self.sender.clone(),
+            self.mailbox.sender(),
+            self.primary_address(),
+            msg,
+            delay,
+            period,
+        ))
     }
 
     /// Forward a transport message to its next routing destination
@@ -222,39 +524,79 @@ impl Context {
     /// [`Context::send_message`] instead, unless you are writing an
     /// external router implementation for ockam node.
     ///
+    /// The message keeps its trace context, or continues the trace
+    /// of the message this worker is currently handling if it has
+    /// none.
+    ///
     /// [`Context::send_message`]: crate::Context::send_message
     /// [`TransportMessage`]: ockam_core::TransportMessage
-    pub async fn forward_message(&self, data: TransportMessage) -> Result<()> {
-        // Resolve the sender for the next hop in the messages route
-        let (reply_tx, mut reply_rx) = channel(1);
-        let next = data.onward_route.next().unwrap(); // TODO: communicate bad routes
-        let req = NodeMessage::SenderReq(next.clone(), reply_tx);
-
-        // First resolve the next hop in the route
-        self.sender.send(req).await.map_err(|e| Error::from(e))?;
-        let (addr, sender, _) = reply_rx
-            .recv()
-            .await
-            .ok_or(Error::InternalIOFailure)??
-            .take_sender()?;
+    pub async fn forward_message(&self, mut data: TransportMessage) -> Result<()> {
+        data.trace = data.trace.or(self.trace).map(TraceContext::next_hop);
+        forward(&self.sender, data).await
+    }
+
+    /// Send a message and wait for a typed reply
+    ///
+    /// The message is sent from a temporary address which acts as
+    /// the correlation id of this request, meaning that only a reply
+    /// sent back along the message's return route will be returned.
+    /// If no reply arrives before `timeout` has elapsed a timeout
+    /// error is returned instead of blocking forever.
+    pub async fn request<Req, Resp, R>(
+        &self,
+        route: R,
+        msg: Req,
+        timeout: Duration,
+    ) -> Result<Routed<Resp>>
+    where
+        R: Into<Route>,
+        Req: Message + Send + 'static,
+        Resp: Message,
+    {
+        let mut child = self.new_context(random::<Address>()).await?;
+        child.trace = self.trace;
+        child.send_message(route, msg).await?;
 
-        // Pack the transport message into a relay message
-        let onward = data.onward_route.clone();
-        let msg = RelayMessage::direct(addr, data, onward);
-        sender.send(msg).await.map_err(|e| Error::from(e))?;
+        let resp = child.receive_timeout::<Resp>(timeout).await?.take();
 
-        Ok(())
+        Ok(resp)
     }
 
     /// Block the current worker to wait for a typed message
     ///
     /// Will return `None` if the corresponding worker has been
     /// stopped, or the underlying Node has shut down.
-    pub async fn receive<'ctx, M: Message>(&'ctx mut self) -> Result<Cancel<'ctx, M>> {
+    pub async fn receive<M: Message>(&mut self) -> Result<Cancel<'_, M>> {
         let (msg, data, addr) = self.next_from_mailbox().await?;
         Ok(Cancel::new(msg, data, addr, self))
     }
 
+    /// Wait for a typed message, giving up after `timeout` has elapsed
+    ///
+    /// Will return a timeout error if no message of the requested
+    /// type arrived in time, which allows workers to recover from
+    /// unresponsive peers.
+    pub async fn receive_timeout<M: Message>(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Cancel<'_, M>> {
+        let (msg, data, addr) = time::timeout(timeout, self.next_from_mailbox())
+            .await
+            .map_err(|_| Error::Timeout)??;
+        Ok(Cancel::new(msg, data, addr, self))
+    }
+
+    /// Check the mailbox for a typed message without blocking
+    ///
+    /// Will return `None` if no message of the requested type is
+    /// currently queued.  Messages of a different type that are
+    /// encountered along the way are re-queued into the mailbox.
+    pub async fn try_receive<M: Message>(&mut self) -> Result<Option<Cancel<'_, M>>> {
+        Ok(self
+            .take_from_mailbox(|_: &M| true)
+            .map(move |(msg, data, addr)| Cancel::new(msg, data, addr, self)))
+    }
+
     /// Block the current worker to wait for a message satisfying a conditional
     ///
     /// Will return `Err` if the corresponding worker has been
@@ -262,32 +604,142 @@ impl Context {
     ///
     /// Internally this function calls `receive` and `.cancel()` in a
     /// loop until a matching message is found.
-    pub async fn receive_match<'ctx, M, F>(&'ctx mut self, check: F) -> Result<Cancel<'ctx, M>>
+    pub async fn receive_match<M, F>(&mut self, check: F) -> Result<Cancel<'_, M>>
+    where
+        M: Message,
+        F: Fn(&M) -> bool,
+    {
+        let (m, data, addr) = self.next_match_from_mailbox(check).await?;
+        Ok(Cancel::new(m, data, addr, self))
+    }
+
+    /// Wait for a message satisfying a conditional, giving up after `timeout`
+    ///
+    /// Behaves like [`Context::receive_match`], but will return a
+    /// timeout error if no matching message arrived in time.
+    ///
+    /// [`Context::receive_match`]: crate::Context::receive_match
+    pub async fn receive_match_timeout<M, F>(
+        &mut self,
+        check: F,
+        timeout: Duration,
+    ) -> Result<Cancel<'_, M>>
+    where
+        M: Message,
+        F: Fn(&M) -> bool,
+    {
+        time::timeout(timeout, self.receive_match(check))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Wait for a message satisfying a conditional until `deadline`
+    ///
+    /// Similar to [`Context::receive_match_timeout`], but taking an
+    /// absolute point in time, which makes it easier to share a
+    /// single deadline between several receive calls.
+    ///
+    /// [`Context::receive_match_timeout`]: crate::Context::receive_match_timeout
+    pub async fn receive_match_deadline<M, F>(
+        &mut self,
+        check: F,
+        deadline: Instant,
+    ) -> Result<Cancel<'_, M>>
     where
         M: Message,
         F: Fn(&M) -> bool,
     {
-        while let Ok((m, data, addr)) = self.next_from_mailbox().await {
-            if check(&m) {
-                // Return a Cancel if the check succeeded
-                return Ok(Cancel::new(m, data, addr, self));
-            } else {
-                // Requeue the message into the mailbox if it didn't
-                let onward = data.onward_route.clone();
-                self.mailbox
-                    .requeue(RelayMessage::direct(addr, data, onward))
-                    .await;
+        time::timeout_at(deadline.into(), self.receive_match(check))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Subscribe this worker to a topic
+    ///
+    /// Messages published to the topic are delivered to this
+    /// worker's mailbox until it unsubscribes, or is stopped.
+    pub async fn subscribe<T: Into<String>>(&self, topic: T) -> Result<()> {
+        let (msg, mut rx) = NodeMessage::subscribe(topic.into(), self.primary_address());
+        self.sender.send(msg).await.map_err(Error::from)?;
+
+        Ok(rx.recv().await.ok_or(Error::InternalIOFailure)??.is_ok()?)
+    }
+
+    /// Unsubscribe this worker from a topic
+    pub async fn unsubscribe<T: Into<String>>(&self, topic: T) -> Result<()> {
+        let (msg, mut rx) = NodeMessage::unsubscribe(topic.into(), self.primary_address());
+        self.sender.send(msg).await.map_err(Error::from)?;
+
+        Ok(rx.recv().await.ok_or(Error::InternalIOFailure)??.is_ok()?)
+    }
+
+    /// Publish a message to all subscribers of a topic
+    ///
+    /// The message is encoded once, and then delivered into the
+    /// mailbox of every subscriber, with this worker's address as
+    /// the return route.  Failing to deliver to one subscriber does
+    /// not affect the others.  Returns the number of subscribers
+    /// that the message was delivered to.
+    pub async fn publish<T, M>(&self, topic: T, msg: M) -> Result<usize>
+    where
+        T: Into<String>,
+        M: Message + Send + 'static,
+    {
+        let topic = topic.into();
+        let (req, mut rx) = NodeMessage::subscribers_request(topic.clone());
+        self.sender.send(req).await.map_err(Error::from)?;
+        let subscribers = rx
+            .recv()
+            .await
+            .ok_or(Error::InternalIOFailure)??
+            .take_subscribers()?;
+
+        let payload = msg.encode()?;
+        let trace = self.outgoing_trace();
+        let mut delivered = 0;
+        for (addr, sender) in subscribers {
+            let route: Route = addr.clone().into();
+            let mut data = TransportMessage::v1(route.clone(), payload.clone());
+            data.return_route.modify().append(self.primary_address());
+            data.message_type = M::message_type();
+            data.trace = Some(trace);
+
+            let msg = RelayMessage::direct(addr.clone(), data, route);
+            match sender.send(msg).await {
+                Ok(()) => delivered += 1,
+                Err(e) => warn!(
+                    "Failed to publish to subscriber {} of topic '{}': {:?}",
+                    addr, topic, e
+                ),
             }
         }
 
-        Err(Error::FailedLoadData.into())
+        Ok(delivered)
+    }
+
+    /// Describe the workers and external routers of this node
+    ///
+    /// See [`NodeInfo`] for details.  The same information is
+    /// available to other nodes via the [`NODE_MANAGER`] worker.
+    ///
+    /// [`NodeInfo`]: crate::NodeInfo
+    /// [`NODE_MANAGER`]: crate::NODE_MANAGER
+    pub async fn node_info(&self) -> Result<NodeInfo> {
+        let (msg, mut reply_rx) = NodeMessage::inspect();
+        self.sender.send(msg).await.map_err(Error::from)?;
+
+        Ok(reply_rx
+            .recv()
+            .await
+            .ok_or(Error::InternalIOFailure)??
+            .take_node_info()?)
     }
 
     /// Return a list of all available worker addresses on a node
     pub async fn list_workers(&self) -> Result<Vec<Address>> {
         let (msg, mut reply_rx) = NodeMessage::list_workers();
 
-        self.sender.send(msg).await.map_err(|e| Error::from(e))?;
+        self.sender.send(msg).await.map_err(Error::from)?;
 
         Ok(reply_rx
             .recv()
@@ -308,38 +760,147 @@ impl Context {
         Ok(rx.recv().await.ok_or(Error::InternalIOFailure)??.is_ok()?)
     }
 
+    /// Remove the router registered for a specific address type
+    ///
+    /// Fails if `addr` is not the router registered for `type_`.
+    pub async fn deregister<A: Into<Address>>(&self, type_: u8, addr: A) -> Result<()> {
+        let addr = addr.into();
+        let (tx, mut rx) = channel(1);
+        self.sender
+            .send(NodeMessage::Deregister(type_, addr, tx))
+            .await
+            .map_err(|_| Error::InternalIOFailure)?;
+
+        Ok(rx.recv().await.ok_or(Error::InternalIOFailure)??.is_ok()?)
+    }
+
     /// A convenience function to get a data 3-tuple from the mailbox
     ///
     /// The reason this function doesn't construct a `Cancel<_, M>` is
     /// to avoid the lifetime collision between the mutation on `self` and the ref to `Context`
     /// passed to `Cancel::new(..)`
-    ///
-    /// This function will block and re-queue messages into the
-    /// mailbox until it can receive the correct message payload.
-    ///
-    /// WARNING: this will temporarily create a busyloop, this
-    /// mechanism should be replaced with a waker system that lets the
-    /// mailbox work not yield another message until the relay worker
-    /// has woken it.
     async fn next_from_mailbox<M: Message>(&mut self) -> Result<(M, TransportMessage, Address)> {
+        self.next_match_from_mailbox(|_: &M| true).await
+    }
+
+    /// Wait for the first message of type `M` that satisfies `check`
+    ///
+    /// Every message in the mailbox is checked once, and the mailbox
+    /// is only checked again after a new message has arrived.
+    /// Messages that don't match stay in the mailbox.
+    async fn next_match_from_mailbox<M, F>(
+        &mut self,
+        check: F,
+    ) -> Result<(M, TransportMessage, Address)>
+    where
+        M: Message,
+        F: Fn(&M) -> bool,
+    {
         loop {
-            let msg = self
-                .mailbox
-                .next()
-                .await
-                .ok_or_else(|| Error::FailedLoadData)?;
-            let (addr, data) = msg.transport();
-
-            // FIXME: make message parsing idempotent to avoid cloning
-            match parser::message(&data.payload).ok() {
-                Some(msg) => return Ok((msg, data, addr)),
-                None => {
-                    let onward = data.onward_route.clone();
-                    self.mailbox
-                        .requeue(RelayMessage::direct(addr, data, onward))
-                        .await;
-                }
+            // Messages queued before the mailbox was closed are still
+            // checked, so the flag is read first
+            let closed = self.mailbox.is_closed();
+            if let Some(found) = self.take_from_mailbox(&check) {
+                return Ok(found);
+            }
+            if closed {
+                return Err(Error::FailedLoadData.into());
             }
+
+            self.mailbox.readable().await;
         }
     }
+
+    /// Take the first queued message of type `M` that satisfies `check`
+    fn take_from_mailbox<M, F>(&mut self, check: F) -> Option<(M, TransportMessage, Address)>
+    where
+        M: Message,
+        F: Fn(&M) -> bool,
+    {
+        let (msg, m) = self.mailbox.take_first(|msg| {
+            let data = msg.direct_message()?;
+
+            // Messages of a different type are never decoded
+            if !parser::type_matches::<M>(data) {
+                return None;
+            }
+
+            // FIXME: make message parsing idempotent to avoid decoding
+            // skipped messages again on the next check
+            parser::message::<M>(&data.payload)
+                .ok()
+                .filter(|m| check(m))
+        })?;
+
+        // Replies sent after receiving continue its trace
+        let (addr, data) = msg.transport();
+        self.trace = data.trace;
+        Some((m, data, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::test_node;
+    use ockam_core::Error;
+    use std::time::Duration;
+
+    #[test]
+    fn receive_timeout_keeps_other_messages() {
+        test_node(|mut ctx| async move {
+            ctx.send_message("app", String::from("hello")).await?;
+
+            let res = ctx.receive_timeout::<u64>(Duration::from_millis(50)).await;
+            assert!(res.is_err());
+
+            let msg = ctx.receive::<String>().await?.take().take();
+            assert_eq!(msg, "hello");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_waits_for_new_messages() {
+        test_node(|mut ctx| async move {
+            ctx.send_message("app", String::from("skipped")).await?;
+            ctx.send_after("app", 42u64, Duration::from_millis(50))?;
+
+            let msg = ctx.receive_timeout::<u64>(Duration::from_secs(5)).await?;
+            assert_eq!(*msg, 42);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_match_keeps_skipped_messages_in_order() {
+        test_node(|mut ctx| async move {
+            for n in 1u64..=3 {
+                ctx.send_message("app", n).await?;
+            }
+
+            assert_eq!(*ctx.receive_match(|n: &u64| *n == 2).await?, 2);
+            assert_eq!(*ctx.receive::<u64>().await?, 1);
+            assert_eq!(*ctx.receive::<u64>().await?, 3);
+            assert!(ctx.try_receive::<u64>().await?.is_none());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn send_without_router() {
+        test_node(|mut ctx| async move {
+            let res = ctx.send_message("9#nowhere", String::from("lost")).await;
+            let code = res.map_err(|e| e.code());
+            assert_eq!(
+                code,
+                Err(Error::from(crate::error::Error::UnknownRouter).code())
+            );
+
+            // The node keeps routing messages
+            ctx.send_message("app", String::from("hello")).await?;
+            let msg = ctx.receive::<String>().await?.take().take();
+            assert_eq!(msg, "hello");
+            Ok(())
+        })
+    }
 }
@@ -26,6 +26,14 @@ pub enum Error {
     InternalIOFailure,
     /// Worker tried to send message from foreign address
     SenderAddressDoesntExist,
+    /// No reply was received before the deadline expired
+    Timeout,
+    /// The receiving worker's mailbox is full
+    MailboxFull,
+    /// The message type does not match the type the receiver expects
+    WrongMessageType,
+    /// No router is registered for the address type
+    UnknownRouter,
 }
 
 impl Error {
@@ -48,6 +56,8 @@ impl From<crate::NodeError> for ockam_core::Error {
             NoSuchWorker(_) => Error::UnknownWorker,
             WorkerExists(_) => Error::WorkerAddressTaken,
             RouterExists => Error::InternalIOFailure,
+            NodeStopping => Error::FailedStartWorker,
+            NoSuchRouter(_) => Error::UnknownRouter,
         }
         .into()
     }
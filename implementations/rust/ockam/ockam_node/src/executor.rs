@@ -1,9 +1,9 @@
 // use crate::message::BaseMessage;
 
-use crate::{relay::RelayMessage, router::Router, NodeMessage};
+use crate::{router::Router, MailboxSender, Metrics, NodeMessage};
 use ockam_core::{Address, Result};
 
-use std::{future::Future, sync::Arc};
+use std::{future::Future, sync::Arc, time::Duration};
 use tokio::{runtime::Runtime, sync::mpsc::Sender};
 
 /// Ockam node and worker executor
@@ -36,12 +36,21 @@ impl Executor {
         self.rt.clone()
     }
 
+    pub(crate) fn metrics(&self) -> Arc<Metrics> {
+        self.router.metrics()
+    }
+
+    /// Set how long to wait for each worker to shut down
+    ///
+    /// When the node is stopped, workers are shut down one by one,
+    /// in reverse start order.  Workers that take longer than this
+    /// timeout are abandoned.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.router.set_shutdown_timeout(timeout);
+    }
+
     /// Initialize the root application worker
-    pub fn initialize_system<S: Into<Address>>(
-        &mut self,
-        address: S,
-        mailbox: Sender<RelayMessage>,
-    ) {
+    pub fn initialize_system<S: Into<Address>>(&mut self, address: S, mailbox: MailboxSender) {
         trace!("Initializing node executor");
         self.router.init(address.into(), mailbox);
     }
@@ -55,7 +64,9 @@ impl Executor {
         let _join = rt.spawn(future);
 
         // Block this task executing the primary message router,
-        // returning any critical failures that it encounters.
+        // returning any critical failures that it encounters.  When
+        // the node is stopped, the router only returns once all
+        // workers have been shut down.
         rt.block_on(self.router.run())
     }
 }
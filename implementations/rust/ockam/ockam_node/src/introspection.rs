@@ -0,0 +1,75 @@
+//! Node and worker introspection
+//!
+//! The state of a node can be queried locally via
+//! [`Context::node_info`], or remotely by sending a
+//! [`NodeManagerRequest`] to the [`NODE_MANAGER`] worker, which is
+//! started on every node.
+//!
+//! [`Context::node_info`]: crate::Context::node_info
+
+use crate::Context;
+use ockam_core::{async_trait::async_trait, Address, AddressSet, Result, Routed, Worker};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::SystemTime};
+
+/// The address of the built-in node manager worker
+pub const NODE_MANAGER: &str = "node_manager";
+
+/// The state of a single worker
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WorkerInfo {
+    /// All addresses of the worker, starting with its primary address
+    pub addresses: AddressSet,
+    /// The Rust type name of the worker
+    pub worker_type: String,
+    /// The number of messages queued in the worker's mailbox
+    pub mailbox_depth: usize,
+    /// The number of messages dropped because the worker's mailbox
+    /// was full
+    pub messages_dropped: u64,
+    /// The number of messages the worker has handled
+    pub messages_handled: u64,
+    /// The number of messages the worker failed to handle
+    pub errors: u64,
+    /// When the worker was started
+    pub started: SystemTime,
+}
+
+/// The state of a node
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NodeInfo {
+    /// All workers running on the node
+    pub workers: Vec<WorkerInfo>,
+    /// The external routers registered for each address type
+    pub routers: BTreeMap<u8, Address>,
+}
+
+/// A request to the node manager worker
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum NodeManagerRequest {
+    /// Reply with the [`NodeInfo`] of the node
+    NodeInfo,
+}
+
+/// A worker answering introspection requests
+pub(crate) struct NodeManager;
+
+#[async_trait]
+impl Worker for NodeManager {
+    type Context = Context;
+    type Message = NodeManagerRequest;
+
+    async fn handle_message(
+        &mut self,
+        ctx: &mut Context,
+        msg: Routed<NodeManagerRequest>,
+    ) -> Result<()> {
+        let reply = msg.reply();
+        match msg.take() {
+            NodeManagerRequest::NodeInfo => {
+                let info = ctx.node_info().await?;
+                ctx.send_message(reply, info).await
+            }
+        }
+    }
+}
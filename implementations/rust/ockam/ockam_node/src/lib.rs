@@ -15,17 +15,37 @@ extern crate tracing;
 mod context;
 mod error;
 mod executor;
+mod introspection;
+mod loopback;
 mod mailbox;
 mod messages;
+mod metrics;
+mod multi;
 mod node;
 mod parser;
 mod relay;
 mod router;
+#[cfg(feature = "sim")]
+mod sim;
+mod supervisor;
+mod timer;
 
 pub use context::*;
 pub use executor::*;
+pub use introspection::{NodeInfo, NodeManagerRequest, WorkerInfo, NODE_MANAGER};
+pub use loopback::{LinkConditions, LoopbackNetwork, LOOPBACK, LOOPBACK_SCHEME};
 pub use mailbox::*;
 pub use messages::*;
+pub use metrics::{
+    worker_samples, MetricKind, Metrics, MetricsExporter, MetricsRequest, MetricsWorker,
+    PrometheusExporter, Sample,
+};
+pub use multi::{Handler, Handlers, MultiWorker};
+pub use supervisor::{RestartPolicy, WorkerFailed};
+pub use timer::TimerHandle;
+
+#[cfg(feature = "sim")]
+pub use sim::{Simulation, DEFAULT_MAX_JITTER};
 
 pub use node::start_node;
 
@@ -42,13 +62,13 @@ use tokio::{runtime::Runtime, task};
 /// as an implementation utility for other ockam utilities that use
 /// tokio.
 #[doc(hidden)]
-pub fn block_future<'r, F>(rt: &'r Runtime, f: F) -> <F as Future>::Output
+pub fn block_future<F>(rt: &Runtime, f: F) -> <F as Future>::Output
 where
     F: Future + Send,
     F::Output: Send,
 {
     task::block_in_place(move || {
         let local = task::LocalSet::new();
-        local.block_on(&rt, f)
+        local.block_on(rt, f)
     })
 }
@@ -0,0 +1,480 @@
+//! An in-memory transport connecting nodes in the same process
+//!
+//! Nodes are attached to a [`LoopbackNetwork`] under a name, which
+//! makes them reachable via addresses of type [`LOOPBACK`], similar
+//! to how the TCP transport makes peers reachable via `type = 1`
+//! addresses.  This allows testing cross-node routing without
+//! opening any sockets.
+//!
+//! ```ignore
+//! let network = LoopbackNetwork::new();
+//! network.attach(&alice_ctx, "alice").await?;
+//! network.attach(&bob_ctx, "bob").await?;
+//!
+//! let route = Route::new()
+//!     .append(LoopbackNetwork::address("bob"))
+//!     .append("echoer");
+//! alice_ctx.send_message(route, "Hello Bob!".to_string()).await?;
+//! ```
+//!
+//! The network can inject latency, message loss, reordering and
+//! partitions between nodes.  All random decisions are drawn from a
+//! seeded generator, which makes them reproducible.
+//!
+//! Loopback addresses can also be written with the
+//! [`LOOPBACK_SCHEME`], as in `loopback://bob => echoer`.
+
+use crate::{context, Context, NodeMessage};
+use ockam_core::{
+    async_trait::async_trait, register_scheme, Address, Result, Routed, RouterMessage, StopReason,
+    TraceContext, TransportMessage, Worker,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::mpsc::Sender, time};
+
+/// The address type of nodes on a loopback network
+pub const LOOPBACK: u8 = 255;
+
+/// The scheme prefix of loopback addresses, as in `loopback://bob`
+pub const LOOPBACK_SCHEME: &str = "loopback://";
+
+const DEFAULT_ADDRESS: &str = "io.ockam.router.loopback";
+
+/// Conditions applied to every message sent across a loopback network
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    /// The time it takes for a message to arrive
+    pub latency: Duration,
+    /// The upper bound of an additional random delay
+    ///
+    /// Messages with different delays can overtake each other.
+    pub reorder: Duration,
+    /// The probability of a message getting lost, from `0.0` to `1.0`
+    pub loss: f64,
+}
+
+/// The fate of a single message sent across the network
+enum Transmission {
+    Deliver(Sender<NodeMessage>, Duration),
+    Drop(&'static str),
+}
+
+struct Network {
+    /// The router handles of all attached nodes
+    nodes: BTreeMap<String, Sender<NodeMessage>>,
+    /// Pairs of nodes that can't reach each other
+    partitions: BTreeSet<(String, String)>,
+    conditions: LinkConditions,
+    rng: StdRng,
+}
+
+/// Order a pair of node names, since partitions are symmetric
+fn link(a: String, b: String) -> (String, String) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+impl Network {
+    fn transmit(&mut self, from: &str, to: &str) -> Transmission {
+        let target = match self.nodes.get(to) {
+            Some(target) => target.clone(),
+            None => return Transmission::Drop("unknown node"),
+        };
+
+        if self.partitions.contains(&link(from.into(), to.into())) {
+            return Transmission::Drop("nodes are partitioned");
+        }
+
+        let LinkConditions {
+            latency,
+            reorder,
+            loss,
+        } = self.conditions;
+
+        if loss > 0.0 && self.rng.gen_bool(loss.min(1.0)) {
+            return Transmission::Drop("message was lost");
+        }
+
+        let reorder = reorder.as_nanos() as u64;
+        let jitter = if reorder > 0 {
+            Duration::from_nanos(self.rng.gen_range(0..=reorder))
+        } else {
+            Duration::from_secs(0)
+        };
+
+        Transmission::Deliver(target, latency + jitter)
+    }
+}
+
+/// An in-memory network of nodes
+///
+/// This handle can be cloned and shared between nodes, even if they
+/// run on different [`Executor`](crate::Executor)s.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    inner: Arc<Mutex<Network>>,
+}
+
+impl Default for LoopbackNetwork {
+    fn default() -> Self {
+        Self::with_seed(rand::random())
+    }
+}
+
+impl LoopbackNetwork {
+    /// Create a new network with a random seed
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new network with a seed for its random decisions
+    pub fn with_seed(seed: u64) -> Self {
+        if let Err(e) = register_scheme(LOOPBACK_SCHEME, LOOPBACK) {
+            warn!("Failed to register loopback address scheme: {:?}", e);
+        }
+
+        Self {
+            inner: Arc::new(Mutex::new(Network {
+                nodes: BTreeMap::new(),
+                partitions: BTreeSet::new(),
+                conditions: LinkConditions::default(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Return the address of a node on a loopback network
+    pub fn address(node: &str) -> Address {
+        format!("{}#{}", LOOPBACK, node).into()
+    }
+
+    /// Set the conditions applied to all messages sent from now on
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.inner.lock().unwrap().conditions = conditions;
+    }
+
+    /// Stop all messages between two nodes, in both directions
+    pub fn partition<A: Into<String>, B: Into<String>>(&self, a: A, b: B) {
+        let link = link(a.into(), b.into());
+        self.inner.lock().unwrap().partitions.insert(link);
+    }
+
+    /// Allow messages between two partitioned nodes again
+    pub fn heal<A: Into<String>, B: Into<String>>(&self, a: A, b: B) {
+        let link = link(a.into(), b.into());
+        self.inner.lock().unwrap().partitions.remove(&link);
+    }
+
+    /// Remove all partitions
+    pub fn heal_all(&self) {
+        self.inner.lock().unwrap().partitions.clear();
+    }
+
+    /// Attach the node of a context to this network under a name
+    ///
+    /// This starts a loopback router on the node, which handles all
+    /// [`LOOPBACK`] addresses.  Attaching a node under a name that is
+    /// already in use replaces the previous node.
+    pub async fn attach<S: Into<String>>(&self, ctx: &Context, node: S) -> Result<()> {
+        let node = node.into();
+        debug!("Attaching node '{}' to loopback network", node);
+
+        let router = LoopbackRouter {
+            node: node.clone(),
+            network: self.clone(),
+        };
+        ctx.start_worker(DEFAULT_ADDRESS, router).await?;
+
+        // Register the router right away, so that messages can be
+        // sent across the network as soon as this function returns
+        trace!("Registering loopback router for type = {}", LOOPBACK);
+        ctx.register(LOOPBACK, DEFAULT_ADDRESS).await?;
+
+        self.inner.lock().unwrap().nodes.insert(node, ctx.router());
+        Ok(())
+    }
+}
+
+/// Forward a message into the node it was sent to
+async fn deliver(target: Sender<NodeMessage>, msg: TransportMessage) {
+    if let Err(e) = context::forward(&target, msg).await {
+        warn!("Failed to deliver loopback message: {}", e);
+    }
+}
+
+/// The router for loopback addresses on a single node
+struct LoopbackRouter {
+    node: String,
+    network: LoopbackNetwork,
+}
+
+#[async_trait]
+impl Worker for LoopbackRouter {
+    type Context = Context;
+    type Message = RouterMessage;
+
+    async fn handle_message(&mut self, _: &mut Context, msg: Routed<RouterMessage>) -> Result<()> {
+        let mut msg = match msg.take() {
+            RouterMessage::Route(msg) => msg,
+            RouterMessage::Register { .. } | RouterMessage::Deregister { .. } => {
+                trace!("Loopback router does not accept registrations");
+                return Ok(());
+            }
+        };
+
+        // Remove the peer node from the route
+        let peer = match msg.onward_route.step() {
+            Some(peer) => String::from_utf8_lossy(&peer).into_owned(),
+            None => return Ok(()),
+        };
+        if msg.onward_route.next().is_none() {
+            warn!("Dropping loopback message to '{}' without recipient", peer);
+            return Ok(());
+        }
+
+        // Let the peer route replies back to this node
+        msg.return_route
+            .modify()
+            .prepend(LoopbackNetwork::address(&self.node));
+        msg.trace = msg.trace.map(TraceContext::next_hop);
+
+        let transmission = self
+            .network
+            .inner
+            .lock()
+            .unwrap()
+            .transmit(&self.node, &peer);
+
+        match transmission {
+            Transmission::Drop(reason) => {
+                debug!(
+                    "Dropping message from '{}' to '{}': {}",
+                    self.node, peer, reason
+                )
+            }
+            Transmission::Deliver(target, delay) if delay == Duration::from_secs(0) => {
+                deliver(target, msg).await
+            }
+            Transmission::Deliver(target, delay) => {
+                tokio::spawn(async move {
+                    time::sleep(delay).await;
+                    deliver(target, msg).await
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn shutdown(&mut self, ctx: &mut Context, _: StopReason) -> Result<()> {
+        // The name may have been taken over by another node since
+        let mut inner = self.network.inner.lock().unwrap();
+        if let Some(router) = inner.nodes.get(&self.node) {
+            if router.same_channel(&ctx.router()) {
+                inner.nodes.remove(&self.node);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::{start_node, test_node};
+    use ockam_core::Route;
+    use std::{
+        sync::mpsc,
+        thread::{self, JoinHandle},
+        time::Instant,
+    };
+    use tokio::sync::oneshot;
+
+    /// Sends every message back to where it came from
+    struct Echo;
+
+    #[async_trait]
+    impl Worker for Echo {
+        type Context = Context;
+        type Message = String;
+
+        async fn handle_message(&mut self, ctx: &mut Context, msg: Routed<String>) -> Result<()> {
+            ctx.send_message(msg.reply(), msg.take()).await
+        }
+    }
+
+    /// A node with an `echoer` worker, running on its own executor
+    struct EchoNode {
+        stop: Option<oneshot::Sender<()>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl EchoNode {
+        fn start(network: &LoopbackNetwork, name: &'static str) -> Self {
+            let network = network.clone();
+            let (ready_tx, ready_rx) = mpsc::channel();
+            let (stop, stopped) = oneshot::channel::<()>();
+
+            let thread = thread::spawn(move || {
+                let (ctx, mut exe) = start_node();
+                exe.execute(async move {
+                    network.attach(&ctx, name).await.unwrap();
+                    ctx.start_worker("echoer", Echo).await.unwrap();
+                    ready_tx.send(()).unwrap();
+
+                    let _ = stopped.await;
+                    ctx.stop().await.unwrap();
+                })
+                .unwrap();
+            });
+
+            ready_rx.recv().unwrap();
+            Self {
+                stop: Some(stop),
+                thread: Some(thread),
+            }
+        }
+    }
+
+    impl Drop for EchoNode {
+        fn drop(&mut self) {
+            let _ = self.stop.take().unwrap().send(());
+            let _ = self.thread.take().unwrap().join();
+        }
+    }
+
+    fn echo_route(node: &str) -> Route {
+        Route::new()
+            .append(LoopbackNetwork::address(node))
+            .append("echoer")
+            .into()
+    }
+
+    #[test]
+    fn delivers_between_executors() {
+        let network = LoopbackNetwork::with_seed(1);
+        let _bob = EchoNode::start(&network, "bob");
+
+        test_node(move |mut ctx| async move {
+            network.attach(&ctx, "alice").await?;
+            ctx.send_message(echo_route("bob"), "hello".to_string())
+                .await?;
+
+            let reply = ctx
+                .receive_timeout::<String>(Duration::from_secs(5))
+                .await?
+                .take();
+            assert_eq!(reply.reply(), echo_route("bob"));
+            assert_eq!(reply.take(), "hello");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn drops_lost_messages() {
+        let network = LoopbackNetwork::with_seed(1);
+        let _bob = EchoNode::start(&network, "bob");
+
+        test_node(move |mut ctx| async move {
+            network.attach(&ctx, "alice").await?;
+            network.set_conditions(LinkConditions {
+                loss: 1.0,
+                ..LinkConditions::default()
+            });
+            ctx.send_message(echo_route("bob"), "hello".to_string())
+                .await?;
+            assert!(ctx
+                .receive_timeout::<String>(Duration::from_millis(100))
+                .await
+                .is_err());
+
+            // Messages to nodes that are not attached are dropped too
+            network.set_conditions(LinkConditions::default());
+            ctx.send_message(echo_route("carol"), "hello".to_string())
+                .await?;
+            assert!(ctx
+                .receive_timeout::<String>(Duration::from_millis(100))
+                .await
+                .is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn delays_messages() {
+        let network = LoopbackNetwork::with_seed(1);
+        let _bob = EchoNode::start(&network, "bob");
+
+        test_node(move |mut ctx| async move {
+            network.attach(&ctx, "alice").await?;
+            network.set_conditions(LinkConditions {
+                latency: Duration::from_millis(50),
+                ..LinkConditions::default()
+            });
+
+            // The message crosses the network twice
+            let sent = Instant::now();
+            ctx.send_message(echo_route("bob"), "hello".to_string())
+                .await?;
+            ctx.receive_timeout::<String>(Duration::from_secs(5))
+                .await?;
+            assert!(sent.elapsed() >= Duration::from_millis(100));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn partitions_nodes() {
+        let network = LoopbackNetwork::with_seed(1);
+        let _bob = EchoNode::start(&network, "bob");
+
+        test_node(move |mut ctx| async move {
+            network.attach(&ctx, "alice").await?;
+            network.partition("bob", "alice");
+            ctx.send_message(echo_route("bob"), "lost".to_string())
+                .await?;
+            assert!(ctx
+                .receive_timeout::<String>(Duration::from_millis(100))
+                .await
+                .is_err());
+
+            network.heal("alice", "bob");
+            ctx.send_message(echo_route("bob"), "hello".to_string())
+                .await?;
+            let reply = ctx
+                .receive_timeout::<String>(Duration::from_secs(5))
+                .await?;
+            assert_eq!(reply.take().take(), "hello");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reattach_under_same_name() {
+        let network = LoopbackNetwork::with_seed(1);
+        let old_bob = EchoNode::start(&network, "bob");
+        let _bob = EchoNode::start(&network, "bob");
+
+        // The old node must not detach its replacement
+        drop(old_bob);
+
+        test_node(move |mut ctx| async move {
+            network.attach(&ctx, "alice").await?;
+            ctx.send_message(echo_route("bob"), "hello".to_string())
+                .await?;
+            let reply = ctx
+                .receive_timeout::<String>(Duration::from_secs(5))
+                .await?;
+            assert_eq!(reply.take().take(), "hello");
+            Ok(())
+        })
+    }
+}
@@ -1,7 +1,208 @@
-use crate::{relay::RelayMessage, Context};
-use ockam_core::{Address, Message, Routed, TransportMessage};
-use std::fmt::{self, Debug, Display, Formatter};
-use tokio::sync::mpsc::{Receiver, Sender};
+use crate::{error::Error, relay::RelayMessage, Context, WorkerInfo};
+use ockam_core::{Address, AddressSet, Message, Routed, StopReason, TransportMessage};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug, Display, Formatter},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tokio::sync::Notify;
+
+/// The number of messages a mailbox can hold by default
+pub const DEFAULT_MAILBOX_CAPACITY: usize = 32;
+
+/// Determine what happens to new messages when a mailbox is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Make the sender wait until there is space in the mailbox
+    Block,
+    /// Drop the new message and return an error to its sender
+    DropNewest,
+    /// Drop the oldest queued message to make space for the new one
+    DropOldest,
+}
+
+/// Per-worker mailbox configuration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MailboxOptions {
+    capacity: usize,
+    overflow: OverflowPolicy,
+}
+
+impl Default for MailboxOptions {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_MAILBOX_CAPACITY,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+impl MailboxOptions {
+    /// Create a new set of mailbox options with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the number of messages the mailbox can hold
+    ///
+    /// A mailbox always has space for at least one message.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Set the policy to apply when the mailbox is full
+    pub fn overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+}
+
+/// The message queue shared between a mailbox and its senders
+#[derive(Debug)]
+struct Queue {
+    messages: Mutex<VecDeque<RelayMessage>>,
+    options: MailboxOptions,
+    /// Signalled whenever a message was added to the queue
+    readable: Notify,
+    /// Signalled whenever a message was taken out of the queue
+    writable: Notify,
+    /// The number of messages dropped because of overflow
+    dropped: AtomicUsize,
+    /// Set once the mailbox no longer accepts new messages
+    closed: AtomicBool,
+    /// Why the mailbox was closed
+    stop_reason: Mutex<Option<StopReason>>,
+    /// The message type the worker expects, if it is known
+    message_type: Option<String>,
+    /// The Rust type name of the worker
+    worker_type: &'static str,
+    /// When the worker was started
+    started: SystemTime,
+    /// The number of messages the worker has handled
+    handled: AtomicU64,
+    /// The number of messages the worker failed to handle
+    errors: AtomicU64,
+}
+
+impl Queue {
+    fn drop_message(&self, msg: &RelayMessage) {
+        warn!("Mailbox full: dropping message for '{}'", msg.addr);
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A send handle to a worker mailbox
+///
+/// This handle is what the router hands out to resolve an address
+/// to a worker.  It applies the mailbox [`OverflowPolicy`] when
+/// delivering messages.
+#[derive(Clone, Debug)]
+pub struct MailboxSender {
+    queue: Arc<Queue>,
+}
+
+impl MailboxSender {
+    /// Deliver a message into the mailbox
+    ///
+    /// If the mailbox is full, this function either waits for space,
+    /// drops the oldest queued message, or returns an error,
+    /// depending on the mailbox's overflow policy.
+    ///
+    /// Messages with a type identifier that doesn't match the type
+    /// the worker expects are rejected.
+    pub async fn send(&self, msg: RelayMessage) -> Result<(), Error> {
+        #[cfg(feature = "sim")]
+        crate::sim::jitter().await;
+
+        let queue = &self.queue;
+        if let (Some(actual), Some(expected)) = (msg.message_type(), &queue.message_type) {
+            if actual != expected.as_str() {
+                warn!(
+                    "Rejecting message of type '{}' for '{}', which expects '{}'",
+                    actual, msg.addr, expected
+                );
+                return Err(Error::WrongMessageType);
+            }
+        }
+
+        loop {
+            {
+                let mut messages = queue.messages.lock().unwrap();
+                if queue.closed.load(Ordering::Acquire) {
+                    return Err(Error::FailedSendMessage);
+                }
+
+                if messages.len() < queue.options.capacity {
+                    messages.push_back(msg);
+                    queue.readable.notify_one();
+                    return Ok(());
+                }
+
+                match queue.options.overflow {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropNewest => {
+                        queue.drop_message(&msg);
+                        return Err(Error::MailboxFull);
+                    }
+                    OverflowPolicy::DropOldest => {
+                        if let Some(oldest) = messages.pop_front() {
+                            queue.drop_message(&oldest);
+                        }
+                        messages.push_back(msg);
+                        queue.readable.notify_one();
+                        return Ok(());
+                    }
+                }
+            }
+
+            // Wait for the worker to take a message out of the queue
+            queue.writable.notified().await;
+        }
+    }
+
+    /// Check whether the mailbox was closed
+    pub fn is_closed(&self) -> bool {
+        self.queue.closed.load(Ordering::Acquire)
+    }
+
+    /// Return the number of messages currently queued
+    pub fn depth(&self) -> usize {
+        self.queue.messages.lock().unwrap().len()
+    }
+
+    /// Describe the worker that owns this mailbox
+    pub(crate) fn worker_info(&self, addresses: AddressSet) -> WorkerInfo {
+        let queue = &self.queue;
+        WorkerInfo {
+            addresses,
+            worker_type: queue.worker_type.to_string(),
+            mailbox_depth: self.depth(),
+            messages_dropped: queue.dropped.load(Ordering::Relaxed) as u64,
+            messages_handled: queue.handled.load(Ordering::Relaxed),
+            errors: queue.errors.load(Ordering::Relaxed),
+            started: queue.started,
+        }
+    }
+
+    /// Stop the mailbox from accepting new messages
+    ///
+    /// Messages that are already queued can still be received, after
+    /// which [`Mailbox::next`] returns `None`.  If the mailbox is
+    /// closed several times, only the first `reason` is kept.
+    pub fn close(&self, reason: StopReason) {
+        // Take the lock to not race with senders checking the flag
+        let _messages = self.queue.messages.lock().unwrap();
+        self.queue.stop_reason.lock().unwrap().get_or_insert(reason);
+        self.queue.closed.store(true, Ordering::Release);
+        self.queue.readable.notify_one();
+        self.queue.writable.notify_waiters();
+    }
+}
 
 /// A mailbox for encoded messages
 ///
@@ -10,27 +211,133 @@ use tokio::sync::mpsc::{Receiver, Sender};
 /// [`Context`](crate::Context).
 #[derive(Debug)]
 pub struct Mailbox {
-    rx: Receiver<RelayMessage>,
-    tx: Sender<RelayMessage>,
+    queue: Arc<Queue>,
 }
 
 impl Mailbox {
-    pub fn new(rx: Receiver<RelayMessage>, tx: Sender<RelayMessage>) -> Self {
-        Self { rx, tx }
+    /// Create a new mailbox
+    ///
+    /// If a `message_type` is given, messages with a different type
+    /// identifier are rejected when they are sent.  The
+    /// `worker_type` is only used for introspection.
+    pub fn new(
+        options: MailboxOptions,
+        message_type: Option<String>,
+        worker_type: &'static str,
+    ) -> Self {
+        Self {
+            queue: Arc::new(Queue {
+                messages: Mutex::new(VecDeque::with_capacity(options.capacity)),
+                options,
+                readable: Notify::new(),
+                writable: Notify::new(),
+                dropped: AtomicUsize::new(0),
+                closed: AtomicBool::new(false),
+                stop_reason: Mutex::new(None),
+                message_type,
+                worker_type,
+                started: SystemTime::now(),
+                handled: AtomicU64::new(0),
+                errors: AtomicU64::new(0),
+            }),
+        }
     }
 
-    pub fn sender(&self) -> Sender<RelayMessage> {
-        self.tx.clone()
+    pub fn sender(&self) -> MailboxSender {
+        MailboxSender {
+            queue: Arc::clone(&self.queue),
+        }
     }
 
     /// Get the next message from the mailbox
+    ///
+    /// Returns `None` once the mailbox was closed and all remaining
+    /// messages have been received.
     pub async fn next(&mut self) -> Option<RelayMessage> {
-        self.rx.recv().await
+        loop {
+            if let Some(msg) = self.try_next() {
+                return Some(msg);
+            }
+
+            if self.is_closed() {
+                return None;
+            }
+
+            self.queue.readable.notified().await;
+        }
+    }
+
+    /// Get the next message from the mailbox if one is available
+    ///
+    /// Unlike [`Mailbox::next`] this function never waits for a new
+    /// message to arrive.
+    pub fn try_next(&mut self) -> Option<RelayMessage> {
+        let msg = self.queue.messages.lock().unwrap().pop_front();
+        if msg.is_some() {
+            self.queue.writable.notify_one();
+        }
+        msg
+    }
+
+    /// Take the first queued message that `select` accepts
+    ///
+    /// `select` returns a value for the messages it accepts, which is
+    /// returned together with the message.  Messages that are skipped
+    /// stay in the mailbox, in their original order.
+    pub(crate) fn take_first<T, F>(&mut self, mut select: F) -> Option<(RelayMessage, T)>
+    where
+        F: FnMut(&RelayMessage) -> Option<T>,
+    {
+        let mut messages = self.queue.messages.lock().unwrap();
+        let (index, value) = messages
+            .iter()
+            .enumerate()
+            .find_map(|(index, msg)| select(msg).map(|value| (index, value)))?;
+        let msg = messages.remove(index)?;
+        drop(messages);
+
+        self.queue.writable.notify_one();
+        Some((msg, value))
+    }
+
+    /// Wait until a message was added to the mailbox, or it was closed
+    ///
+    /// A message that arrived since the last call, while nobody was
+    /// waiting, completes the wait right away.
+    pub(crate) async fn readable(&self) {
+        self.queue.readable.notified().await
     }
 
     /// If a message wasn't expected, requeue it
+    ///
+    /// Requeued messages were already accounted for when they were
+    /// first delivered, so they bypass the mailbox capacity.
     pub async fn requeue(&self, msg: RelayMessage) {
-        self.tx.send(msg).await.unwrap();
+        self.queue.messages.lock().unwrap().push_back(msg);
+        self.queue.readable.notify_one();
+    }
+
+    /// Check whether the mailbox was closed
+    pub fn is_closed(&self) -> bool {
+        self.queue.closed.load(Ordering::Acquire)
+    }
+
+    /// Return why the mailbox was closed, if it was
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        *self.queue.stop_reason.lock().unwrap()
+    }
+
+    /// Count a message that the worker has handled
+    pub(crate) fn record_handled(&self, failed: bool) {
+        self.queue.handled.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.queue.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Return the number of messages dropped because the mailbox was full
+    pub fn dropped(&self) -> usize {
+        self.queue.dropped.load(Ordering::Relaxed)
     }
 }
 
@@ -1,31 +1,43 @@
-use crate::{error::Error, relay::RelayMessage};
-use ockam_core::{Address, AddressSet};
+use crate::{error::Error, MailboxSender, NodeInfo};
+use ockam_core::{Address, AddressSet, StopReason};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 /// Messages sent from the Node to the Executor
 #[derive(Debug)]
 pub enum NodeMessage {
     /// Start a new worker and store the send handle
-    StartWorker(AddressSet, Sender<RelayMessage>, Sender<NodeReplyResult>),
+    StartWorker(AddressSet, MailboxSender, Sender<NodeReplyResult>),
     /// Return a list of all worker addresses
     ListWorkers(Sender<NodeReplyResult>),
     /// Stop an existing worker
-    StopWorker(Address, Sender<NodeReplyResult>),
+    StopWorker(Address, StopReason, Sender<NodeReplyResult>),
     /// Stop the node (and all workers)
     StopNode,
+    /// Confirm that a worker has finished shutting down
+    StopAck(Address),
     /// Request the sender for a worker address
     SenderReq(Address, Sender<NodeReplyResult>),
     /// Register a new router for a route id type
     Router(u8, Address, Sender<NodeReplyResult>),
+    /// Remove the router for a route id type
+    Deregister(u8, Address, Sender<NodeReplyResult>),
     /// Check if a given address is already registered
     CheckAddress(AddressSet, Sender<NodeReplyResult>),
+    /// Subscribe a worker to a topic
+    Subscribe(String, Address, Sender<NodeReplyResult>),
+    /// Unsubscribe a worker from a topic
+    Unsubscribe(String, Address, Sender<NodeReplyResult>),
+    /// Request the senders for all subscribers of a topic
+    SubscribersReq(String, Sender<NodeReplyResult>),
+    /// Describe the node, its workers and external routers
+    Inspect(Sender<NodeReplyResult>),
 }
 
 impl NodeMessage {
     /// Create a start worker message
     pub fn start_worker(
         address: AddressSet,
-        sender: Sender<RelayMessage>,
+        sender: MailboxSender,
     ) -> (Self, Receiver<NodeReplyResult>) {
         let (tx, rx) = channel(1);
         (Self::StartWorker(address, sender, tx), rx)
@@ -38,9 +50,9 @@ impl NodeMessage {
     }
 
     /// Create a stop worker message and reply receiver
-    pub fn stop_worker(address: Address) -> (Self, Receiver<NodeReplyResult>) {
+    pub fn stop_worker(address: Address, reason: StopReason) -> (Self, Receiver<NodeReplyResult>) {
         let (tx, rx) = channel(1);
-        (Self::StopWorker(address, tx), rx)
+        (Self::StopWorker(address, reason, tx), rx)
     }
 
     /// Create a stop node message
@@ -59,6 +71,30 @@ impl NodeMessage {
         let (tx, rx) = channel(1);
         (Self::CheckAddress(addrs, tx), rx)
     }
+
+    /// Create a node introspection message and reply receiver
+    pub fn inspect() -> (Self, Receiver<NodeReplyResult>) {
+        let (tx, rx) = channel(1);
+        (Self::Inspect(tx), rx)
+    }
+
+    /// Create a topic subscription message and reply receiver
+    pub fn subscribe(topic: String, addr: Address) -> (Self, Receiver<NodeReplyResult>) {
+        let (tx, rx) = channel(1);
+        (Self::Subscribe(topic, addr, tx), rx)
+    }
+
+    /// Create a topic unsubscription message and reply receiver
+    pub fn unsubscribe(topic: String, addr: Address) -> (Self, Receiver<NodeReplyResult>) {
+        let (tx, rx) = channel(1);
+        (Self::Unsubscribe(topic, addr, tx), rx)
+    }
+
+    /// Create a topic subscribers request message and reply receiver
+    pub fn subscribers_request(topic: String) -> (Self, Receiver<NodeReplyResult>) {
+        let (tx, rx) = channel(1);
+        (Self::SubscribersReq(topic, tx), rx)
+    }
 }
 
 pub type NodeReplyResult = Result<NodeReply, NodeError>;
@@ -75,11 +111,15 @@ pub enum NodeReply {
         /// The address a message is being sent to
         addr: Address,
         /// The relay sender
-        sender: Sender<RelayMessage>,
+        sender: MailboxSender,
         /// Indicate whether the relay message needs to be constructed
         /// with router wrapping.
         wrap: bool,
     },
+    /// Message senders to all subscribers of a topic
+    Subscribers(Vec<(Address, MailboxSender)>),
+    /// The state of the node
+    NodeInfo(NodeInfo),
 }
 
 /// Failure states from a router command
@@ -88,6 +128,8 @@ pub enum NodeError {
     NoSuchWorker(Address),
     WorkerExists(Address),
     RouterExists,
+    NodeStopping,
+    NoSuchRouter(u8),
 }
 
 impl NodeReply {
@@ -107,32 +149,62 @@ impl NodeReply {
         Err(NodeError::RouterExists)
     }
 
+    pub fn node_stopping() -> NodeReplyResult {
+        Err(NodeError::NodeStopping)
+    }
+
+    pub fn no_such_router(tt: u8) -> NodeReplyResult {
+        Err(NodeError::NoSuchRouter(tt))
+    }
+
     pub fn workers(v: Vec<Address>) -> NodeReplyResult {
         Ok(Self::Workers(v))
     }
 
-    pub fn sender(addr: Address, sender: Sender<RelayMessage>, wrap: bool) -> NodeReplyResult {
+    pub fn sender(addr: Address, sender: MailboxSender, wrap: bool) -> NodeReplyResult {
         Ok(NodeReply::Sender { addr, sender, wrap })
     }
 
-    pub fn take_sender(self) -> Result<(Address, Sender<RelayMessage>, bool), Error> {
+    pub fn take_sender(self) -> Result<(Address, MailboxSender, bool), Error> {
         match self {
             Self::Sender { addr, sender, wrap } => Ok((addr, sender, wrap)),
-            _ => Err(Error::InternalIOFailure.into()),
+            _ => Err(Error::InternalIOFailure),
+        }
+    }
+
+    pub fn subscribers(v: Vec<(Address, MailboxSender)>) -> NodeReplyResult {
+        Ok(Self::Subscribers(v))
+    }
+
+    pub fn take_subscribers(self) -> Result<Vec<(Address, MailboxSender)>, Error> {
+        match self {
+            Self::Subscribers(s) => Ok(s),
+            _ => Err(Error::InternalIOFailure),
+        }
+    }
+
+    pub fn node_info(info: NodeInfo) -> NodeReplyResult {
+        Ok(Self::NodeInfo(info))
+    }
+
+    pub fn take_node_info(self) -> Result<NodeInfo, Error> {
+        match self {
+            Self::NodeInfo(info) => Ok(info),
+            _ => Err(Error::InternalIOFailure),
         }
     }
 
     pub fn take_workers(self) -> Result<Vec<Address>, Error> {
         match self {
             Self::Workers(w) => Ok(w),
-            _ => Err(Error::InternalIOFailure.into()),
+            _ => Err(Error::InternalIOFailure),
         }
     }
 
     pub fn is_ok(self) -> Result<(), Error> {
         match self {
             Self::Ok => Ok(()),
-            _ => Err(Error::InternalIOFailure.into()),
+            _ => Err(Error::InternalIOFailure),
         }
     }
 }
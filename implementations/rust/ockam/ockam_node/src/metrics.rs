@@ -0,0 +1,337 @@
+//! Node metrics
+//!
+//! Every node keeps a registry of counters, which all of its workers
+//! share via [`Context::metrics`].  The router counts the messages
+//! routed to each address, and the addresses of each type it failed to
+//! resolve, while transports and other components add their own
+//! counters.  Counters labelled with an address or a peer should be
+//! removed once it is gone, so that the number of counters stays
+//! bounded on long-running nodes.
+//!
+//! A [`MetricsWorker`] combines these counters with the current state
+//! of all workers, such as their mailbox depth, and renders them with
+//! a [`MetricsExporter`] whenever it receives a [`MetricsRequest`].
+//!
+//! ```ignore
+//! ctx.start_worker("metrics", MetricsWorker::new(PrometheusExporter))
+//!     .await?;
+//! ```
+//!
+//! [`Context::metrics`]: crate::Context::metrics
+
+use crate::{Context, NodeInfo};
+use ockam_core::{async_trait::async_trait, Result, Routed, Worker};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+/// Messages the router delivered, per address
+pub const MESSAGES_ROUTED: &str = "ockam_messages_routed_total";
+/// Addresses the router failed to resolve, per address type
+pub const RESOLUTION_FAILURES: &str = "ockam_sender_resolution_failures_total";
+/// Messages queued in a worker's mailbox
+pub const MAILBOX_DEPTH: &str = "ockam_mailbox_depth";
+/// Messages dropped because a worker's mailbox was full
+pub const MESSAGES_DROPPED: &str = "ockam_mailbox_dropped_total";
+/// Messages a worker has handled
+pub const MESSAGES_HANDLED: &str = "ockam_messages_handled_total";
+/// Messages a worker failed to handle
+pub const WORKER_ERRORS: &str = "ockam_worker_errors_total";
+
+/// The kind of a metric
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    /// A value that only ever increases
+    Counter,
+    /// A value that can go up and down
+    Gauge,
+}
+
+/// A single measurement
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// The name of the metric
+    pub name: String,
+    /// The kind of the metric
+    pub kind: MetricKind,
+    /// Labels distinguishing samples of the same metric
+    pub labels: Vec<(String, String)>,
+    /// The measured value
+    pub value: f64,
+}
+
+impl Sample {
+    fn new(name: &str, kind: MetricKind, labels: &[(&str, &str)], value: f64) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+        }
+    }
+}
+
+type CounterKey = (&'static str, Vec<(&'static str, String)>);
+
+/// The counters of a node
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<CounterKey, u64>>,
+}
+
+impl Metrics {
+    /// Create an empty set of counters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increase a counter, creating it if it doesn't exist yet
+    pub fn increment(&self, name: &'static str, labels: &[(&'static str, &str)], by: u64) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry(Self::key(name, labels))
+            .or_insert(0) += by;
+    }
+
+    /// Return the current value of a counter
+    pub fn get(&self, name: &'static str, labels: &[(&'static str, &str)]) -> u64 {
+        self.counters
+            .lock()
+            .unwrap()
+            .get(&Self::key(name, labels))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Return the values of all counters
+    pub fn samples(&self) -> Vec<Sample> {
+        self.counters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((name, labels), value)| {
+                let labels: Vec<_> = labels.iter().map(|(k, v)| (*k, v.as_str())).collect();
+                Sample::new(name, MetricKind::Counter, &labels, *value as f64)
+            })
+            .collect()
+    }
+
+    /// Remove all counters that carry a label with the given value
+    ///
+    /// This is used to drop the counters of a worker or peer that is
+    /// gone.
+    pub fn remove(&self, label: &str, value: &str) {
+        self.counters
+            .lock()
+            .unwrap()
+            .retain(|(_, labels), _| !labels.iter().any(|(k, v)| *k == label && v == value));
+    }
+
+    fn key(name: &'static str, labels: &[(&'static str, &str)]) -> CounterKey {
+        let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        (name, labels)
+    }
+}
+
+/// Describe the state of all workers on a node as samples
+pub fn worker_samples(info: &NodeInfo) -> Vec<Sample> {
+    let mut samples = vec![];
+    for worker in &info.workers {
+        let address = worker.addresses.first().to_string();
+        let labels = [("address", address.as_str())];
+
+        samples.push(Sample::new(
+            MAILBOX_DEPTH,
+            MetricKind::Gauge,
+            &labels,
+            worker.mailbox_depth as f64,
+        ));
+        samples.push(Sample::new(
+            MESSAGES_DROPPED,
+            MetricKind::Counter,
+            &labels,
+            worker.messages_dropped as f64,
+        ));
+        samples.push(Sample::new(
+            MESSAGES_HANDLED,
+            MetricKind::Counter,
+            &labels,
+            worker.messages_handled as f64,
+        ));
+        samples.push(Sample::new(
+            WORKER_ERRORS,
+            MetricKind::Counter,
+            &labels,
+            worker.errors as f64,
+        ));
+    }
+    samples
+}
+
+/// Render samples into a format understood by a monitoring system
+pub trait MetricsExporter: Send + 'static {
+    /// Render a set of samples
+    fn export(&mut self, samples: &[Sample]) -> String;
+}
+
+/// An exporter for the Prometheus text format
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrometheusExporter;
+
+/// Escape a label value as required by the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl MetricsExporter for PrometheusExporter {
+    fn export(&mut self, samples: &[Sample]) -> String {
+        // All samples of a metric need to be grouped under its TYPE line
+        let mut samples: Vec<&Sample> = samples.iter().collect();
+        samples.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut out = String::new();
+        let mut current: Option<&str> = None;
+        for sample in samples {
+            if current != Some(sample.name.as_str()) {
+                let kind = match sample.kind {
+                    MetricKind::Counter => "counter",
+                    MetricKind::Gauge => "gauge",
+                };
+                let _ = writeln!(out, "# TYPE {} {}", sample.name, kind);
+                current = Some(sample.name.as_str());
+            }
+
+            out.push_str(&sample.name);
+            if !sample.labels.is_empty() {
+                let labels: Vec<_> = sample
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+                    .collect();
+                let _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(out, " {}", sample.value);
+        }
+        out
+    }
+}
+
+/// A request to a metrics worker
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MetricsRequest {
+    /// Reply with all metrics of the node, rendered by the exporter
+    Scrape,
+}
+
+/// A worker serving the metrics of its node
+///
+/// Each [`MetricsRequest::Scrape`] is answered with a `String`
+/// containing the output of the worker's exporter.
+pub struct MetricsWorker<E> {
+    exporter: E,
+}
+
+impl<E: MetricsExporter> MetricsWorker<E> {
+    /// Create a metrics worker rendering metrics with `exporter`
+    pub fn new(exporter: E) -> Self {
+        Self { exporter }
+    }
+}
+
+#[async_trait]
+impl<E: MetricsExporter> Worker for MetricsWorker<E> {
+    type Context = Context;
+    type Message = MetricsRequest;
+
+    async fn handle_message(
+        &mut self,
+        ctx: &mut Context,
+        msg: Routed<MetricsRequest>,
+    ) -> Result<()> {
+        let reply = msg.reply();
+        match msg.take() {
+            MetricsRequest::Scrape => {
+                let mut samples = ctx.metrics().samples();
+                samples.extend(worker_samples(&ctx.node_info().await?));
+
+                let text = self.exporter.export(&samples);
+                ctx.send_message(reply, text).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{node::test_node, MailboxOptions, OverflowPolicy};
+    use std::time::Duration;
+
+    /// Tells `app` about the first message, and then takes its time
+    struct Slow;
+
+    #[async_trait]
+    impl Worker for Slow {
+        type Context = Context;
+        type Message = u64;
+
+        async fn handle_message(&mut self, ctx: &mut Context, msg: Routed<u64>) -> Result<()> {
+            if msg.take() == 0 {
+                ctx.send_message("app", String::from("busy")).await?;
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn remove_counters_by_label() {
+        let metrics = Metrics::new();
+        metrics.increment(MESSAGES_ROUTED, &[("address", "a")], 2);
+        metrics.increment(WORKER_ERRORS, &[("address", "a")], 1);
+        metrics.increment(MESSAGES_ROUTED, &[("address", "b")], 3);
+
+        metrics.remove("address", "a");
+        assert_eq!(metrics.get(MESSAGES_ROUTED, &[("address", "a")]), 0);
+        assert_eq!(metrics.get(MESSAGES_ROUTED, &[("address", "b")]), 3);
+        assert_eq!(metrics.samples().len(), 1);
+    }
+
+    #[test]
+    fn export_dropped_messages() {
+        test_node(|mut ctx| async move {
+            let opts = MailboxOptions::new()
+                .capacity(1)
+                .overflow(OverflowPolicy::DropOldest);
+            ctx.start_worker_with_mailbox("slow", Slow, opts).await?;
+
+            ctx.send_message("slow", 0u64).await?;
+            ctx.receive::<String>().await?;
+
+            // Only the last message fits into the mailbox
+            for n in 1u64..=3 {
+                ctx.send_message("slow", n).await?;
+            }
+
+            let info = ctx.node_info().await?;
+            let slow = info
+                .workers
+                .iter()
+                .find(|w| w.addresses.first() == "slow".into())
+                .unwrap();
+            assert_eq!(slow.messages_dropped, 2);
+
+            let text = PrometheusExporter.export(&worker_samples(&info));
+            assert!(text.contains("# TYPE ockam_mailbox_dropped_total counter\n"));
+            assert!(text.contains("ockam_mailbox_dropped_total{address=\"0#slow\"} 2\n"));
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,151 @@
+//! Workers handling several message types
+//!
+//! A regular [`Worker`] has a single message type, which means that
+//! a worker accepting different kinds of messages needs a wrapping
+//! enum.  A [`MultiWorker`] instead registers one [`Handler`]
+//! implementation per message type it wants to receive.
+//!
+//! Incoming payloads are decoded with each registered message type
+//! in registration order, and the first type that decodes
+//! successfully is passed to its handler.  Types that don't match
+//! the message's type identifier are skipped.  Payloads that none of the
+//! types can decode are passed to [`MultiWorker::fallback`], which
+//! every multi-message worker has to provide.
+
+use crate::{parser, Context};
+use ockam_core::{
+    async_trait::async_trait, Address, Any, Message, Result, Routed, StopReason, TransportMessage,
+    Worker,
+};
+use std::{future::Future, pin::Pin};
+
+/// A worker which handles several message types
+///
+/// Each handled message type needs a [`Handler`] implementation, and
+/// has to be registered in [`MultiWorker::handlers`].  Start this
+/// worker with [`Context::start_multi_worker`].
+///
+/// [`Context::start_multi_worker`]: crate::Context::start_multi_worker
+#[async_trait]
+pub trait MultiWorker: Send + 'static {
+    /// Register the message types handled by this worker
+    fn handlers(handlers: &mut Handlers<Self>)
+    where
+        Self: Sized;
+
+    /// Override initialisation behaviour
+    async fn initialize(&mut self, _context: &mut Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Override shutdown behaviour
+    async fn shutdown(&mut self, _context: &mut Context, _reason: StopReason) -> Result<()> {
+        Ok(())
+    }
+
+    /// Handle a message that none of the registered types can decode
+    async fn fallback(&mut self, context: &mut Context, msg: Routed<Any>) -> Result<()>;
+}
+
+/// Handle one of the message types of a [`MultiWorker`]
+#[async_trait]
+pub trait Handler<M: Message>: MultiWorker {
+    /// Handle a decoded message
+    async fn handle(&mut self, context: &mut Context, msg: Routed<M>) -> Result<()>;
+}
+
+type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Try to decode a payload for a single handler
+///
+/// Returns `None` if the payload is not of type `M`.
+type Dispatch<W> = for<'a> fn(
+    &'a mut W,
+    &'a mut Context,
+    &TransportMessage,
+    &Address,
+) -> Option<HandlerFuture<'a>>;
+
+fn dispatch<'a, W, M>(
+    worker: &'a mut W,
+    ctx: &'a mut Context,
+    transport: &TransportMessage,
+    addr: &Address,
+) -> Option<HandlerFuture<'a>>
+where
+    W: Handler<M>,
+    M: Message,
+{
+    if !parser::type_matches::<M>(transport) {
+        return None;
+    }
+
+    let msg = parser::message::<M>(&transport.payload).ok()?;
+    Some(worker.handle(ctx, Routed::v1(msg, addr.clone(), transport.clone())))
+}
+
+/// The set of message types handled by a [`MultiWorker`]
+pub struct Handlers<W> {
+    handlers: Vec<Dispatch<W>>,
+}
+
+impl<W: MultiWorker> Handlers<W> {
+    /// Register a message type
+    ///
+    /// Message types are tried in the order they were registered.
+    pub fn on<M: Message>(&mut self) -> &mut Self
+    where
+        W: Handler<M>,
+    {
+        self.handlers.push(dispatch::<W, M>);
+        self
+    }
+}
+
+/// Adapter running a [`MultiWorker`] as a regular worker
+pub(crate) struct Multi<W> {
+    worker: W,
+    handlers: Vec<Dispatch<W>>,
+}
+
+impl<W: MultiWorker> Multi<W> {
+    pub(crate) fn new(worker: W) -> Self {
+        let mut handlers = Handlers { handlers: vec![] };
+        W::handlers(&mut handlers);
+
+        Self {
+            worker,
+            handlers: handlers.handlers,
+        }
+    }
+}
+
+#[async_trait]
+impl<W: MultiWorker> Worker for Multi<W> {
+    type Context = Context;
+    type Message = Any;
+
+    async fn initialize(&mut self, ctx: &mut Context) -> Result<()> {
+        self.worker.initialize(ctx).await
+    }
+
+    async fn shutdown(&mut self, ctx: &mut Context, reason: StopReason) -> Result<()> {
+        self.worker.shutdown(ctx, reason).await
+    }
+
+    async fn handle_message(&mut self, ctx: &mut Context, msg: Routed<Any>) -> Result<()> {
+        let addr = msg.msg_addr();
+        let transport = msg.into_transport_message();
+
+        for dispatch in &self.handlers {
+            if let Some(handle) = dispatch(&mut self.worker, ctx, &transport, &addr) {
+                return handle.await;
+            }
+        }
+
+        trace!("No handler of worker {} accepted the message", addr);
+        self.worker
+            .fallback(ctx, Routed::v1(Any, addr, transport))
+            .await
+    }
+}
@@ -1,8 +1,13 @@
-use crate::{relay, Context, Executor, Mailbox, NodeMessage};
-use ockam_core::Address;
-use std::sync::Arc;
+use crate::{
+    introspection::NodeManager, relay, Context, Executor, Mailbox, MailboxOptions, MailboxSender,
+    Metrics, NodeManagerRequest, NodeMessage, NODE_MANAGER,
+};
+use ockam_core::{Address, Message};
+#[cfg(test)]
+use std::future::Future;
+use std::{any::type_name, sync::Arc};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::mpsc::Sender;
 use tracing_subscriber::{filter::LevelFilter, fmt, EnvFilter};
 
 /// A minimal worker implementation that does nothing
@@ -10,13 +15,16 @@ pub struct NullWorker;
 
 impl NullWorker {
     /// Create and register a new NullWorker context
-    pub(crate) fn new(rt: Arc<Runtime>, addr: &Address, tx: Sender<NodeMessage>) -> Context {
+    #[allow(clippy::new_ret_no_self)]
+    pub(crate) fn new(
+        rt: Arc<Runtime>,
+        addr: &Address,
+        tx: Sender<NodeMessage>,
+        metrics: Arc<Metrics>,
+    ) -> Context {
         // Create a new Mailbox and Context
-        let (mb_tx, mb_rx) = channel(32);
-        let mb = Mailbox::new(mb_rx, mb_tx.clone());
-        let ctx = Context::new(rt, tx, addr.into(), mb);
-
-        ctx
+        let mb = Mailbox::new(MailboxOptions::default(), None, type_name::<Context>());
+        Context::new(rt, tx, addr.into(), mb, metrics)
     }
 }
 
@@ -25,6 +33,23 @@ impl ockam_core::Worker for NullWorker {
     type Message = (); // This message type is never used
 }
 
+/// Spawn the node manager worker, returning a send handle to it
+pub(crate) fn start_node_manager(
+    rt: Arc<Runtime>,
+    tx: Sender<NodeMessage>,
+    metrics: Arc<Metrics>,
+) -> MailboxSender {
+    let addr: Address = NODE_MANAGER.into();
+    let mb = Mailbox::new(
+        MailboxOptions::default(),
+        NodeManagerRequest::message_type(),
+        type_name::<NodeManager>(),
+    );
+    let ctx = Context::new(Arc::clone(&rt), tx, addr.into(), mb, metrics);
+
+    relay::build::<NodeManager, NodeManagerRequest>(rt.as_ref(), NodeManager, ctx)
+}
+
 pub fn start_node() -> (Context, Executor) {
     setup_tracing();
 
@@ -35,17 +60,49 @@ pub fn start_node() -> (Context, Executor) {
 
     // The root application worker needs a mailbox and relay to accept
     // messages from workers, and to buffer incoming transcoded data.
-    let ctx = NullWorker::new(exe.runtime(), &addr, exe.sender());
+    let ctx = NullWorker::new(exe.runtime(), &addr, exe.sender(), exe.metrics());
 
     // Build a mailbox worker to buffer messages
-    let sender = relay::build_root::<NullWorker, _>(exe.runtime(), &ctx.mailbox);
+    let sender = relay::build_root(&ctx.mailbox);
 
     // Register this mailbox handle with the executor
     exe.initialize_system("app", sender);
 
+    // Every node can be inspected via the node manager
+    let sender = start_node_manager(exe.runtime(), exe.sender(), exe.metrics());
+    exe.initialize_system(NODE_MANAGER, sender);
+
     (ctx, exe)
 }
 
+/// Run a test on a new node, and stop the node once the test is done
+///
+/// A test that panics or returns an error fails, instead of leaving
+/// the node running forever.
+#[cfg(test)]
+pub(crate) fn test_node<F, Fut>(test: F)
+where
+    F: FnOnce(Context) -> Fut,
+    Fut: Future<Output = ockam_core::Result<()>> + Send + 'static,
+{
+    let (ctx, mut exe) = start_node();
+    let router = exe.sender();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let test = test(ctx);
+
+    exe.execute(async move {
+        let res = tokio::spawn(test).await;
+        let _ = router.send(NodeMessage::StopNode).await;
+        tx.send(res).unwrap();
+    })
+    .unwrap();
+
+    match rx.recv().unwrap() {
+        Ok(res) => res.unwrap(),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
 /// Utility to setup tracing-subscriber from the environment
 fn setup_tracing() {
     fmt()
@@ -54,5 +111,6 @@ fn setup_tracing() {
                 .add_directive(LevelFilter::INFO.into())
                 .add_directive("ockam_node=info".parse().unwrap())
         }))
-        .init();
+        .try_init()
+        .ok();
 }
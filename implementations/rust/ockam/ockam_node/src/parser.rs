@@ -1,4 +1,15 @@
-use ockam_core::{Message, Result};
+use ockam_core::{Message, Result, TransportMessage};
+
+/// Check whether a message has the type that a receiver expects
+///
+/// Messages without a type identifier, as well as receivers that
+/// accept any message type, always match.
+pub(crate) fn type_matches<M: Message>(data: &TransportMessage) -> bool {
+    match (&data.message_type, M::message_type()) {
+        (Some(actual), Some(expected)) => actual == &expected,
+        _ => true,
+    }
+}
 
 // TODO: this function can not mutate the data vector it is given, and
 // thus copies its contents when using the fallback parsing strategy.
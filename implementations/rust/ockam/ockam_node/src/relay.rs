@@ -15,19 +15,21 @@
 //! The `Relay` is then responsible for turning the message back into
 //! a type and notifying the companion actor.
 
-use crate::{parser, Context, Mailbox};
+use crate::{error::Error, parser, Context, Mailbox, MailboxSender};
 use ockam_core::{
-    Address, Message, Result, Route, Routed, RouterMessage, TransportMessage, Worker,
+    Address, Message, Result, Route, Routed, RouterMessage, StopReason, TraceContext,
+    TransportMessage, Worker,
 };
-use std::{marker::PhantomData, sync::Arc};
+use std::marker::PhantomData;
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::Instrument;
 
 /// A message addressed to a relay
 #[derive(Clone, Debug)]
 pub struct RelayMessage {
-    addr: Address,
+    pub(crate) addr: Address,
     data: RelayPayload,
+    #[allow(dead_code)]
     onward: Route,
 }
 
@@ -45,14 +47,31 @@ impl RelayMessage {
     #[inline]
     pub fn pre_router(addr: Address, data: TransportMessage, onward: Route) -> Self {
         let route = data.return_route.clone();
+        let trace = data.trace;
         let r_msg = RouterMessage::Route(data);
         Self {
             addr,
-            data: RelayPayload::PreRouter(r_msg.encode().unwrap(), route),
+            data: RelayPayload::PreRouter(r_msg.encode().unwrap(), route, trace),
             onward,
         }
     }
 
+    /// Return the type identifier of a message addressed to a user worker
+    pub(crate) fn message_type(&self) -> Option<&str> {
+        match self.data {
+            RelayPayload::Direct(ref msg) => msg.message_type.as_deref(),
+            RelayPayload::PreRouter(_, _, _) => None,
+        }
+    }
+
+    /// Return the transport message addressed to a user worker
+    pub(crate) fn direct_message(&self) -> Option<&TransportMessage> {
+        match self.data {
+            RelayPayload::Direct(ref msg) => Some(msg),
+            RelayPayload::PreRouter(_, _, _) => None,
+        }
+    }
+
     /// Consume this message into its base components
     #[inline]
     pub fn transport(self) -> (Address, TransportMessage) {
@@ -69,7 +88,7 @@ impl RelayMessage {
 #[derive(Clone, Debug)]
 pub enum RelayPayload {
     Direct(TransportMessage),
-    PreRouter(Vec<u8>, Route),
+    PreRouter(Vec<u8>, Route, Option<TraceContext>),
 }
 
 pub struct Relay<W, M>
@@ -97,37 +116,64 @@ where
 
     /// Convenience function to handle an incoming direct message
     #[inline]
-    fn handle_direct(&mut self, msg: &TransportMessage, msg_addr: Address) -> Result<(M, Route)> {
+    fn handle_direct(msg: &TransportMessage, msg_addr: Address) -> Result<(M, Route)> {
         let TransportMessage {
             ref payload,
             ref return_route,
+            ref message_type,
             ..
         } = msg;
 
-        parser::message::<M>(payload)
-            .map_err(|e| {
-                error!("Failed to decode message payload for worker {}", msg_addr);
-                e.into()
-            })
-            .map(|m| (m, return_route.clone()))
+        if !parser::type_matches::<M>(msg) {
+            error!(
+                "Worker {} received a message of type '{}', but expects '{}'",
+                msg_addr,
+                message_type.as_deref().unwrap_or_default(),
+                M::message_type().unwrap_or_default()
+            );
+            return Err(Error::WrongMessageType.into());
+        }
+
+        let res = parser::message::<M>(payload);
+        if res.is_err() {
+            error!("Failed to decode message payload for worker {}", msg_addr);
+        }
+        res.map(|m| (m, return_route.clone()))
     }
 
     #[inline]
-    fn handle_pre_router(&mut self, msg: &Vec<u8>, msg_addr: Address) -> Result<M> {
-        M::decode(&msg).map_err(|e| {
+    fn handle_pre_router(msg: &Vec<u8>, msg_addr: Address) -> Result<M> {
+        let res = M::decode(msg);
+        if res.is_err() {
             error!(
                 "Failed to decode wrapped router message for worker {}.  \
 Is your router accepting the correct message type? (ockam_core::RouterMessage)",
                 msg_addr
             );
-            e.into()
-        })
+        }
+        res
     }
 
     async fn run(mut self) {
-        self.worker.initialize(&mut self.ctx).await.unwrap();
+        if let Err(e) = Self::run_worker(&mut self.worker, &mut self.ctx, false).await {
+            error!("Worker {} failed: {}", self.ctx.primary_address(), e);
+        }
+    }
+
+    /// Drive a worker until its mailbox is closed
+    ///
+    /// Errors returned from `initialize` are always passed back to
+    /// the caller.  Errors returned from `handle_message` are logged,
+    /// and only stop the worker if `fail_fast` is set, in which case
+    /// the worker is shut down with `StopReason::Error`.
+    pub(crate) async fn run_worker(
+        worker: &mut W,
+        ctx: &mut Context,
+        fail_fast: bool,
+    ) -> Result<()> {
+        worker.initialize(ctx).await?;
 
-        while let Some(RelayMessage { addr, data, .. }) = self.ctx.mailbox.next().await {
+        while let Some(RelayMessage { addr, data, .. }) = ctx.mailbox.next().await {
             // Extract the message type based on the relay message
             // wrap state.  Messages addressed to a router will be of
             // type `RouterMessage`, while generic userspace workers
@@ -135,11 +181,12 @@ Is your router accepting the correct message type? (ockam_core::RouterMessage)",
             let (msg, _, transport_message) =
                 match (|data| -> Result<(M, Route, TransportMessage)> {
                     Ok(match data {
-                        RelayPayload::Direct(trans_msg) => self
-                            .handle_direct(&trans_msg, addr.clone())
-                            .map(|(msg, r)| (msg, r, trans_msg))?,
-                        RelayPayload::PreRouter(enc_msg, route) => {
-                            self.handle_pre_router(&enc_msg, addr.clone()).map(|m| {
+                        RelayPayload::Direct(trans_msg) => {
+                            Self::handle_direct(&trans_msg, addr.clone())
+                                .map(|(msg, r)| (msg, r, trans_msg))?
+                        }
+                        RelayPayload::PreRouter(enc_msg, route, trace) => {
+                            Self::handle_pre_router(&enc_msg, addr.clone()).map(|m| {
                                 (
                                     m,
                                     route.clone(),
@@ -148,6 +195,8 @@ Is your router accepting the correct message type? (ockam_core::RouterMessage)",
                                         return_route: Route::new().into(),
                                         onward_route: route,
                                         payload: enc_msg,
+                                        message_type: None,
+                                        trace,
                                     },
                                 )
                             })?
@@ -159,13 +208,31 @@ Is your router accepting the correct message type? (ockam_core::RouterMessage)",
                     Err(_) => continue, // Handler functions must log
                 };
 
+            // Messages sent while handling this one continue its
+            // trace, and all log output goes into a span for this hop
+            ctx.trace = transport_message.trace;
+            let span = match ctx.trace {
+                Some(trace) => info_span!("hop", worker = %addr, trace = %trace),
+                None => info_span!("hop", worker = %addr),
+            };
+
             // Wrap the user message in a `Routed` to provide return
             // route information via a composition side-channel
             let routed = Routed::v1(msg, addr.clone(), transport_message);
 
             // Call the worker handle function
-            match self.worker.handle_message(&mut self.ctx, routed).await {
+            let result = worker.handle_message(ctx, routed).instrument(span).await;
+            ctx.trace = None;
+            ctx.mailbox.record_handled(result.is_err());
+
+            match result {
                 Ok(()) => {}
+                Err(e) if fail_fast => {
+                    if let Err(shutdown_err) = worker.shutdown(ctx, StopReason::Error).await {
+                        error!("Worker {} failed to shut down: {}", addr, shutdown_err);
+                    }
+                    return Err(e);
+                }
                 Err(e) => {
                     error!("Worker {} error while handling message: {}", addr, e);
                     continue;
@@ -173,49 +240,31 @@ Is your router accepting the correct message type? (ockam_core::RouterMessage)",
             }
         }
 
-        self.worker.shutdown(&mut self.ctx).unwrap();
-    }
-
-    /// Run the inner worker and restart it if errors occurs
-    async fn run_mailbox(mut rx: Receiver<RelayMessage>, mb_tx: Sender<RelayMessage>) {
-        // Relay messages into the worker mailbox
-        while let Some(enc) = rx.recv().await {
-            match mb_tx.send(enc.clone()).await {
-                Ok(x) => x,
-                Err(_) => panic!("Failed to route message to address '{}'", enc.addr),
-            };
-        }
+        // A mailbox is only closed by the router, which always
+        // provides a reason
+        let reason = ctx.mailbox.stop_reason().unwrap_or(StopReason::Requested);
+        worker.shutdown(ctx, reason).await
     }
 }
 
 /// Build and spawn a new worker relay, returning a send handle to it
-pub(crate) fn build<W, M>(rt: &Runtime, worker: W, ctx: Context) -> Sender<RelayMessage>
+pub(crate) fn build<W, M>(rt: &Runtime, worker: W, ctx: Context) -> MailboxSender
 where
     W: Worker<Context = Context, Message = M>,
     M: Message + Send + 'static,
 {
-    let (tx, rx) = channel(32);
-    let mb_tx = ctx.mailbox.sender();
+    let sender = ctx.mailbox.sender();
     let relay = Relay::<W, M>::new(worker, ctx);
 
-    rt.spawn(Relay::<W, M>::run_mailbox(rx, mb_tx));
     rt.spawn(relay.run());
-    tx
+    sender
 }
 
-/// Build and spawn the root application relay
+/// Build the root application relay
 ///
 /// The root relay is different from normal worker relays because its
 /// message inbox is never automatically run, and instead needs to be
 /// polled via a `receive()` call.
-pub(crate) fn build_root<W, M>(rt: Arc<Runtime>, mailbox: &Mailbox) -> Sender<RelayMessage>
-where
-    W: Worker<Context = Context, Message = M>,
-    M: Message + Send + 'static,
-{
-    let (tx, rx) = channel(32);
-
-    let mb_tx = mailbox.sender();
-    rt.spawn(Relay::<W, M>::run_mailbox(rx, mb_tx));
-    tx
+pub(crate) fn build_root(mailbox: &Mailbox) -> MailboxSender {
+    mailbox.sender()
 }
@@ -1,9 +1,20 @@
-use crate::{error::Error, relay::RelayMessage, NodeMessage, NodeReply, NodeReplyResult};
-use ockam_core::{Address, AddressSet, Result};
-use std::{collections::BTreeMap, sync::Arc};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use crate::{
+    error::Error, metrics, MailboxSender, Metrics, NodeInfo, NodeMessage, NodeReply,
+    NodeReplyResult,
+};
+use ockam_core::{Address, AddressSet, Result, StopReason};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time,
+};
 
-type RelaySender = Arc<Sender<RelayMessage>>;
+/// The default time to wait for each worker to shut down
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// A combined address type and local worker router
 ///
@@ -20,7 +31,7 @@ pub struct Router {
     /// For each address a worker listens to a reference to the same
     /// sender will be store in this map, as to allow alias addresses
     /// to be checked for collisions.
-    internal: BTreeMap<Address, RelaySender>,
+    internal: BTreeMap<Address, MailboxSender>,
     /// Additional address map
     ///
     /// Each worker has a primary address, with secondary addresses
@@ -30,6 +41,18 @@ pub struct Router {
     addr_map: BTreeMap<Address, AddressSet>,
     /// Externally registered router components
     external: BTreeMap<u8, Address>,
+    /// Primary addresses of the workers subscribed to each topic
+    topics: BTreeMap<String, BTreeSet<Address>>,
+    /// Primary worker addresses, in the order they were started
+    start_order: Vec<Address>,
+    /// Stop requests waiting for a worker to finish shutting down
+    pending_stops: BTreeMap<Address, Vec<Sender<NodeReplyResult>>>,
+    /// Set once the node has started shutting down
+    stopping: bool,
+    /// How long to wait for each worker to shut down
+    shutdown_timeout: Duration,
+    /// Counters shared with all workers of this node
+    metrics: Arc<Metrics>,
     /// Receiver for messages from node
     receiver: Receiver<NodeMessage>,
     /// Keeping a copy of the channel sender to pass out
@@ -56,69 +79,151 @@ impl Router {
             internal: BTreeMap::new(),
             addr_map: BTreeMap::new(),
             external: BTreeMap::new(),
+            topics: BTreeMap::new(),
+            start_order: vec![],
+            pending_stops: BTreeMap::new(),
+            stopping: false,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            metrics: Arc::new(Metrics::new()),
             receiver,
             sender,
         }
     }
 
-    pub fn init(&mut self, addr: Address, mb: Sender<RelayMessage>) {
-        self.internal.insert(addr, Arc::new(mb));
+    pub fn init(&mut self, addr: Address, mb: MailboxSender) {
+        self.internal.insert(addr.clone(), mb);
+        self.addr_map.insert(addr.clone(), addr.clone().into());
+        self.start_order.push(addr);
     }
 
     pub fn sender(&self) -> Sender<NodeMessage> {
         self.sender.clone()
     }
 
+    /// Return the counters of this node
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Set how long to wait for each worker to shut down
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
     /// Block current task running this router.  Return fatal errors
     pub async fn run(&mut self) -> Result<()> {
-        use NodeMessage::*;
         while let Some(msg) = self.receiver.recv().await {
             match msg {
-                // Internal registration commands
-                Router(tt, addr, sender) if !self.external.contains_key(&tt) => {
-                    trace!("Registering new router for type {}", tt);
-
-                    self.external.insert(tt, addr);
-                    sender
-                        .send(NodeReply::ok())
-                        .await
-                        .map_err(|_| Error::InternalIOFailure)?
+                NodeMessage::StopNode => {
+                    self.stop_node().await?;
+                    break;
                 }
-                Router(_, _, sender) => sender
-                    .send(NodeReply::router_exists())
-                    .await
-                    .map_err(|_| Error::InternalIOFailure)?,
+                msg => self.handle_msg(msg).await?,
+            }
+        }
 
-                // Basic worker control
-                StartWorker(addr, sender, ref reply) => {
-                    self.start_worker(addr, sender, reply).await?
-                }
-                StopWorker(ref addr, ref reply) => self.stop_worker(addr, reply).await?,
+        Ok(())
+    }
 
-                // Check whether a set of addresses is available
-                CheckAddress(ref addrs, ref reply) => {
-                    self.check_addr_collisions(addrs, reply).await?
-                }
+    async fn handle_msg(&mut self, msg: NodeMessage) -> Result<()> {
+        use NodeMessage::*;
+        match msg {
+            // Internal registration commands
+            Router(tt, addr, sender) if !self.external.contains_key(&tt) => {
+                trace!("Registering new router for type {}", tt);
 
-                // Basic node control
-                StopNode => {
-                    self.internal.clear();
-                    break;
-                }
-                ListWorkers(sender) => sender
-                    .send(NodeReply::workers(self.internal.keys().cloned().collect()))
+                self.external.insert(tt, addr);
+                sender
+                    .send(NodeReply::ok())
                     .await
-                    .map_err(|_| Error::InternalIOFailure)?,
-
-                // Handle route/ sender requests
-                SenderReq(ref addr, ref reply) => match determine_type(addr) {
-                    RouteType::Internal(ref addr) => self.resolve(addr, reply, false).await?,
-                    RouteType::External(tt) => {
-                        let addr = self.router_addr(tt)?;
-                        self.resolve(&addr, reply, true).await?
-                    }
+                    .map_err(|_| Error::InternalIOFailure)?
+            }
+            Router(_, _, sender) => sender
+                .send(NodeReply::router_exists())
+                .await
+                .map_err(|_| Error::InternalIOFailure)?,
+            Deregister(tt, ref addr, ref sender) => self.deregister(tt, addr, sender).await?,
+
+            // Basic worker control
+            StartWorker(_, _, ref reply) if self.stopping => reply
+                .send(NodeReply::node_stopping())
+                .await
+                .map_err(|_| Error::InternalIOFailure)?,
+            StartWorker(addr, sender, ref reply) => self.start_worker(addr, sender, reply).await?,
+            StopWorker(addr, reason, reply) => self.stop_worker(&addr, reason, reply).await,
+            StopAck(ref addr) => self.finish_stop(addr).await,
+
+            // Check whether a set of addresses is available
+            CheckAddress(ref addrs, ref reply) => self.check_addr_collisions(addrs, reply).await?,
+
+            // Topic subscriptions
+            Subscribe(topic, addr, reply) => self.subscribe(topic, &addr, &reply).await?,
+            Unsubscribe(ref topic, ref addr, ref reply) => {
+                self.unsubscribe(topic, addr, reply).await?
+            }
+            SubscribersReq(ref topic, ref reply) => self.resolve_topic(topic, reply).await?,
+
+            // Basic node control
+            StopNode => trace!("Node is already shutting down"),
+            ListWorkers(sender) => sender
+                .send(NodeReply::workers(self.internal.keys().cloned().collect()))
+                .await
+                .map_err(|_| Error::InternalIOFailure)?,
+            Inspect(sender) => sender
+                .send(NodeReply::node_info(self.node_info()))
+                .await
+                .map_err(|_| Error::InternalIOFailure)?,
+
+            // Handle route/ sender requests
+            SenderReq(ref addr, ref reply) => match determine_type(addr) {
+                RouteType::Internal(ref addr) => self.resolve(addr, reply, false).await?,
+                RouteType::External(tt) => match self.external.get(&tt).cloned() {
+                    Some(router) => self.resolve(&router, reply, true).await?,
+                    None => self.no_router(tt, reply).await,
                 },
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Gracefully shut down all workers
+    ///
+    /// Workers are stopped one by one, in the reverse order of how
+    /// they were started.  Each worker's mailbox is closed, so that
+    /// it can process the messages that are still queued, and then
+    /// run its shutdown handler.  Router requests are still handled
+    /// while waiting, so that shutting down workers can send their
+    /// final messages.
+    async fn stop_node(&mut self) -> Result<()> {
+        info!("Stopping node with {} workers", self.start_order.len());
+        self.stopping = true;
+
+        while let Some(addr) = self.start_order.pop() {
+            match self.internal.get(&addr) {
+                Some(sender) => sender.close(StopReason::NodeShutdown),
+                None => continue,
             }
+
+            debug!("Waiting for worker '{}' to shut down", addr);
+            let deadline = time::sleep(self.shutdown_timeout);
+            tokio::pin!(deadline);
+
+            loop {
+                tokio::select! {
+                    _ = &mut deadline => {
+                        warn!("Worker '{}' did not shut down in time", addr);
+                        break;
+                    }
+                    msg = self.receiver.recv() => match msg {
+                        Some(NodeMessage::StopAck(ref ack)) if ack == &addr => break,
+                        Some(msg) => self.handle_msg(msg).await?,
+                        None => return Ok(()),
+                    }
+                }
+            }
+
+            self.finish_stop(&addr).await;
         }
 
         Ok(())
@@ -127,14 +232,14 @@ impl Router {
     async fn start_worker(
         &mut self,
         addrs: AddressSet,
-        sender: Sender<RelayMessage>,
+        sender: MailboxSender,
         reply: &Sender<NodeReplyResult>,
     ) -> Result<()> {
         trace!("Starting new worker '{}'", addrs.first());
-        let sender = Arc::new(sender);
         addrs.iter().for_each(|addr| {
-            self.internal.insert(addr.clone(), Arc::clone(&sender));
+            self.internal.insert(addr.clone(), sender.clone());
         });
+        self.start_order.push(addrs.first());
         self.addr_map.insert(addrs.first(), addrs);
 
         // For now we just send an OK back -- in the future we need to
@@ -146,25 +251,67 @@ impl Router {
         Ok(())
     }
 
-    async fn stop_worker(&mut self, addr: &Address, reply: &Sender<NodeReplyResult>) -> Result<()> {
+    /// Stop a worker by its primary address
+    ///
+    /// The reply is only sent once the worker has confirmed that it
+    /// has shut down.
+    async fn stop_worker(
+        &mut self,
+        addr: &Address,
+        reason: StopReason,
+        reply: Sender<NodeReplyResult>,
+    ) {
         trace!("Stopping worker '{}'", addr);
 
-        let addrs = self.addr_map.remove(addr).unwrap();
-
-        match addrs.iter().fold(Some(()), |opt, addr| {
-            match (opt, self.internal.remove(addr)) {
-                (Some(_), Some(_)) => Some(()),
-                (Some(_), None) => None,
-                (None, _) => None,
+        // Close the mailbox to let the worker shut down once it has
+        // processed all remaining messages
+        match (self.addr_map.get(addr), self.internal.get(addr)) {
+            (Some(_), Some(sender)) => {
+                sender.close(reason);
+                self.pending_stops
+                    .entry(addr.clone())
+                    .or_default()
+                    .push(reply);
+            }
+            _ => {
+                // The requesting worker might not be waiting for a reply
+                let _ = reply.send(NodeReply::no_such_worker(addr.clone())).await;
             }
-        }) {
-            Some(_) => reply.send(NodeReply::ok()),
-            None => reply.send(NodeReply::no_such_worker(addr.clone())),
         }
-        .await
-        .map_err(|_| Error::InternalIOFailure)?;
+    }
 
-        Ok(())
+    /// Deregister a worker that has shut down
+    ///
+    /// All stop requests waiting for this worker are answered.
+    async fn finish_stop(&mut self, addr: &Address) {
+        trace!("Worker '{}' has shut down", addr);
+
+        // Make sure that no more messages can be delivered, in case
+        // the worker shut down without being asked to
+        if let Some(sender) = self.internal.get(addr) {
+            sender.close(StopReason::Requested);
+        }
+        self.remove_worker(addr);
+
+        for reply in self.pending_stops.remove(addr).unwrap_or_default() {
+            // A worker stopping itself does not wait for a reply
+            let _ = reply.send(NodeReply::ok()).await;
+        }
+    }
+
+    /// Remove all addresses and topic subscriptions of a worker
+    fn remove_worker(&mut self, primary: &Address) {
+        self.start_order.retain(|addr| addr != primary);
+        self.topics.values_mut().for_each(|subscribers| {
+            subscribers.remove(primary);
+        });
+
+        if let Some(addrs) = self.addr_map.remove(primary) {
+            addrs.iter().for_each(|addr| {
+                self.internal.remove(addr);
+                self.metrics.remove("address", &addr.to_string());
+            });
+        }
     }
 
     /// Receive an address and resolve it to a sender
@@ -180,25 +327,140 @@ impl Router {
     ) -> Result<()> {
         trace!("Resolvivg worker address '{}'", addr);
 
-        match self.internal.get(addr) {
-            Some(sender) => reply.send(NodeReply::sender(
-                addr.clone(),
-                sender.as_ref().clone(),
-                wrap,
-            )),
-            None => reply.send(NodeReply::no_such_worker(addr.clone())),
+        let msg = match self.internal.get(addr) {
+            Some(sender) => {
+                let label = addr.to_string();
+                let labels = [("address", label.as_str())];
+                self.metrics.increment(metrics::MESSAGES_ROUTED, &labels, 1);
+                NodeReply::sender(addr.clone(), sender.clone(), wrap)
+            }
+            None => {
+                // Unknown addresses are not bounded, their types are
+                let label = addr.tt.to_string();
+                let labels = [("type", label.as_str())];
+                self.metrics
+                    .increment(metrics::RESOLUTION_FAILURES, &labels, 1);
+                NodeReply::no_such_worker(addr.clone())
+            }
+        };
+
+        // The requesting worker might not be waiting for a reply
+        let _ = reply.send(msg).await;
+
+        Ok(())
+    }
+
+    /// Reply to a request for an address type without a router
+    async fn no_router(&self, tt: u8, reply: &Sender<NodeReplyResult>) {
+        trace!("No router registered for type {}", tt);
+
+        let label = tt.to_string();
+        let labels = [("type", label.as_str())];
+        self.metrics
+            .increment(metrics::RESOLUTION_FAILURES, &labels, 1);
+
+        // The requesting worker might not be waiting for a reply
+        let _ = reply.send(NodeReply::no_such_router(tt)).await;
+    }
+
+    /// Subscribe a worker to a topic
+    async fn subscribe(
+        &mut self,
+        topic: String,
+        addr: &Address,
+        reply: &Sender<NodeReplyResult>,
+    ) -> Result<()> {
+        if self.addr_map.contains_key(addr) {
+            trace!("Subscribing worker '{}' to topic '{}'", addr, topic);
+            self.topics.entry(topic).or_default().insert(addr.clone());
+            reply.send(NodeReply::ok())
+        } else {
+            reply.send(NodeReply::no_such_worker(addr.clone()))
         }
         .await
-        .expect("Ockam node internal I/O failed!");
+        .map_err(|_| Error::InternalIOFailure.into())
+    }
 
-        Ok(())
+    /// Unsubscribe a worker from a topic
+    async fn unsubscribe(
+        &mut self,
+        topic: &str,
+        addr: &Address,
+        reply: &Sender<NodeReplyResult>,
+    ) -> Result<()> {
+        trace!("Unsubscribing worker '{}' from topic '{}'", addr, topic);
+        if let Some(subscribers) = self.topics.get_mut(topic) {
+            subscribers.remove(addr);
+        }
+
+        reply
+            .send(NodeReply::ok())
+            .await
+            .map_err(|_| Error::InternalIOFailure.into())
+    }
+
+    /// Resolve all subscribers of a topic to their senders
+    async fn resolve_topic(&self, topic: &str, reply: &Sender<NodeReplyResult>) -> Result<()> {
+        let subscribers = self
+            .topics
+            .get(topic)
+            .into_iter()
+            .flatten()
+            .filter_map(|addr| {
+                self.internal
+                    .get(addr)
+                    .map(|sender| (addr.clone(), sender.clone()))
+            })
+            .collect();
+
+        reply
+            .send(NodeReply::subscribers(subscribers))
+            .await
+            .map_err(|_| Error::InternalIOFailure.into())
+    }
+
+    /// Describe all workers and external routers of this node
+    fn node_info(&self) -> NodeInfo {
+        let workers = self
+            .addr_map
+            .iter()
+            .filter_map(|(primary, addrs)| {
+                self.internal
+                    .get(primary)
+                    .map(|sender| sender.worker_info(addrs.clone()))
+            })
+            .collect();
+
+        NodeInfo {
+            workers,
+            routers: self.external.clone(),
+        }
     }
 
-    fn router_addr(&mut self, tt: u8) -> Result<Address> {
-        self.external
-            .get(&tt)
-            .cloned()
-            .ok_or(Error::InternalIOFailure.into())
+    /// Remove the router for an address type
+    ///
+    /// The router is only removed if it is still registered under
+    /// the given address.
+    async fn deregister(
+        &mut self,
+        tt: u8,
+        addr: &Address,
+        reply: &Sender<NodeReplyResult>,
+    ) -> Result<()> {
+        let msg = match self.external.get(&tt) {
+            Some(router) if router == addr => {
+                trace!("Deregistering router for type {}", tt);
+                self.external.remove(&tt);
+                NodeReply::ok()
+            }
+            _ => NodeReply::no_such_router(tt),
+        };
+
+        reply
+            .send(msg)
+            .await
+            .map_err(|_| Error::InternalIOFailure)?;
+        Ok(())
     }
 
     /// Check if an address is already in-use by another worker
@@ -208,7 +470,7 @@ impl Router {
         reply: &Sender<NodeReplyResult>,
     ) -> Result<()> {
         if let Some(addr) = addrs.iter().fold(None, |acc, addr| {
-            match (acc, self.internal.contains_key(&addr)) {
+            match (acc, self.internal.contains_key(addr)) {
                 (None, true) => Some(addr.clone()),
                 (None, false) => None,
                 // If a collision was already found, ignore further collisions
@@ -0,0 +1,238 @@
+//! Deterministic simulation of ockam nodes
+//!
+//! A [`Simulation`] runs any number of logical nodes on a single
+//! threaded runtime, with a virtual clock that only advances when all
+//! workers are idle.  Timeouts, timers and shutdown deadlines thus
+//! complete instantly, but in the same order as they would in real
+//! time.
+//!
+//! Message delivery is delayed by a random amount of virtual time,
+//! drawn from a generator seeded by the simulation seed.  Trace ids
+//! and other random values of the simulated nodes are drawn from the
+//! same generator.  Different
+//! seeds explore different message interleavings, while running a
+//! simulation again with the same seed replays the exact same
+//! interleaving.  This makes it possible to reproduce race conditions
+//! between workers and nodes.
+//!
+//! This module is only available with the `sim` feature.
+
+use crate::{
+    node::{self, NullWorker},
+    relay,
+    router::Router,
+    Context, NODE_MANAGER,
+};
+use ockam_core::Address;
+use rand::{
+    distributions::{Distribution, Standard},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use std::{cell::RefCell, future::Future, sync::Arc, time::Duration};
+use tokio::{
+    runtime::{Builder, Runtime},
+    time,
+};
+
+/// The default upper bound for the delivery delay of a message
+pub const DEFAULT_MAX_JITTER: Duration = Duration::from_millis(10);
+
+struct Scheduler {
+    rng: StdRng,
+    max_jitter: Duration,
+}
+
+thread_local! {
+    /// The scheduler of the simulation running on this thread
+    ///
+    /// All tasks of a simulation run on the thread that called
+    /// [`Simulation::run`], which is what makes the simulation
+    /// deterministic.
+    static SCHEDULER: RefCell<Option<Scheduler>> = const { RefCell::new(None) };
+}
+
+/// Delay the delivery of a message, if a simulation is running
+pub(crate) async fn jitter() {
+    let delay = SCHEDULER.with(|scheduler| {
+        scheduler.borrow_mut().as_mut().map(|s| {
+            let max = s.max_jitter.as_nanos() as u64;
+            Duration::from_nanos(s.rng.gen_range(0..=max))
+        })
+    });
+
+    if let Some(delay) = delay {
+        time::sleep(delay).await;
+    }
+}
+
+/// Draw a random value, if a simulation is running
+pub(crate) fn random<T>() -> Option<T>
+where
+    Standard: Distribution<T>,
+{
+    SCHEDULER.with(|scheduler| scheduler.borrow_mut().as_mut().map(|s| s.rng.gen()))
+}
+
+/// A deterministic executor for testing multi-node protocols
+///
+/// ```ignore
+/// let mut sim = Simulation::new(42);
+/// let alice = sim.start_node();
+/// let bob = sim.start_node();
+///
+/// sim.run(async move {
+///     // Drive both nodes from their application contexts
+/// });
+/// ```
+pub struct Simulation {
+    rt: Arc<Runtime>,
+    seed: u64,
+    max_jitter: Duration,
+    nodes: usize,
+}
+
+impl Simulation {
+    /// Create a new simulation from a seed
+    pub fn new(seed: u64) -> Self {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        Self {
+            rt: Arc::new(rt),
+            seed,
+            max_jitter: DEFAULT_MAX_JITTER,
+            nodes: 0,
+        }
+    }
+
+    /// Set the upper bound for the delivery delay of a message
+    ///
+    /// A larger bound makes it more likely for messages sent by
+    /// different workers to overtake each other.  Messages sent by
+    /// the same worker are always delivered in order.
+    pub fn max_jitter(mut self, max_jitter: Duration) -> Self {
+        self.max_jitter = max_jitter;
+        self
+    }
+
+    /// Return the seed of this simulation
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Start a new logical node, returning its application context
+    ///
+    /// Every node has its own router, and thus its own set of worker
+    /// addresses.  Nodes only start processing messages once the
+    /// simulation is run.
+    pub fn start_node(&mut self) -> Context {
+        let mut router = Router::new();
+        let addr: Address = "app".into();
+
+        let ctx = NullWorker::new(
+            Arc::clone(&self.rt),
+            &addr,
+            router.sender(),
+            router.metrics(),
+        );
+        router.init(addr, relay::build_root(&ctx.mailbox));
+
+        let node_manager =
+            node::start_node_manager(Arc::clone(&self.rt), router.sender(), router.metrics());
+        router.init(NODE_MANAGER.into(), node_manager);
+
+        let node = self.nodes;
+        self.nodes += 1;
+        self.rt.spawn(async move {
+            if let Err(e) = router.run().await {
+                error!("Simulated node {} failed: {}", node, e);
+            }
+        });
+
+        ctx
+    }
+
+    /// Run the simulation until `future` completes
+    pub fn run<F: Future>(&self, future: F) -> F::Output {
+        SCHEDULER.with(|scheduler| {
+            *scheduler.borrow_mut() = Some(Scheduler {
+                rng: StdRng::seed_from_u64(self.seed),
+                max_jitter: self.max_jitter,
+            })
+        });
+
+        let output = self.rt.block_on(async {
+            time::pause();
+            future.await
+        });
+
+        SCHEDULER.with(|scheduler| *scheduler.borrow_mut() = None);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ockam_core::{async_trait::async_trait, Result, Routed, TraceContext, Worker};
+
+    /// Forwards every number it receives to the application context
+    struct Forward;
+
+    #[async_trait]
+    impl Worker for Forward {
+        type Context = Context;
+        type Message = u64;
+
+        async fn handle_message(&mut self, ctx: &mut Context, msg: Routed<u64>) -> Result<()> {
+            ctx.send_message("app", msg.take()).await
+        }
+    }
+
+    /// Send numbers through several workers, and return the order in
+    /// which they arrive back, with their trace ids
+    fn delivery_order(seed: u64) -> Vec<(u64, Option<TraceContext>)> {
+        let mut sim = Simulation::new(seed);
+        let mut ctx = sim.start_node();
+
+        sim.run(async move {
+            for i in 0..4 {
+                ctx.start_worker(Address::from(format!("forward_{}", i)), Forward)
+                    .await
+                    .unwrap();
+            }
+            for n in 0..16u64 {
+                ctx.send_message(format!("forward_{}", n % 4), n)
+                    .await
+                    .unwrap();
+            }
+
+            let mut order = vec![];
+            for _ in 0..16 {
+                let msg = ctx.receive::<u64>().await.unwrap().take();
+                let n = *msg;
+                order.push((n, msg.into_transport_message().trace));
+            }
+            ctx.stop().await.unwrap();
+            order
+        })
+    }
+
+    #[test]
+    fn same_seed_same_order() {
+        let order = delivery_order(42);
+        assert_eq!(order.len(), 16);
+        assert_eq!(order, delivery_order(42));
+    }
+
+    #[test]
+    fn other_seed_other_order() {
+        let numbers =
+            |seed| -> Vec<u64> { delivery_order(seed).into_iter().map(|(n, _)| n).collect() };
+        let order = numbers(42);
+
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..16).collect::<Vec<_>>());
+        assert!((0..4).any(|seed| numbers(seed) != order));
+    }
+}
@@ -0,0 +1,371 @@
+//! Worker supervision and restart strategies
+//!
+//! A supervised worker is not started from a worker instance, but
+//! from a factory closure.  Whenever the worker fails, either by
+//! returning an error from `initialize` or `handle_message`, or by
+//! panicking, the supervisor consults its [`RestartPolicy`] to decide
+//! whether to create a fresh worker instance from the factory.
+//!
+//! The worker context, and thus the worker's addresses and mailbox,
+//! are kept alive across restarts.  Messages that were queued while
+//! the worker was restarting will be delivered to the new instance.
+//!
+//! Restart limits only apply to failures in a row.  A worker instance
+//! that runs for [`STABLE_PERIOD`] without failing has recovered, and
+//! its next failure is handled like the first one.
+
+use crate::{relay::Relay, Context, MailboxSender};
+use ockam_core::{Address, Message, Worker};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    task::{self, Poll},
+    thread,
+    time::Duration,
+};
+use tokio::{
+    runtime::Runtime,
+    time::{self, Instant},
+};
+
+/// How long a restarted worker has to run to reset its restart count
+pub(crate) const STABLE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Determine what happens when a supervised worker fails
+///
+/// Restart limits count failures in a row.  A worker that runs for a
+/// minute without failing starts counting again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Immediately restart the failed worker
+    ///
+    /// Once the worker failed more than `max_restarts` times in a row
+    /// the failure is escalated to the parent instead.
+    OneForOne {
+        /// The number of restarts after which to give up
+        max_restarts: u32,
+    },
+    /// Restart the failed worker after an exponentially growing delay
+    ///
+    /// The first restart is delayed by `initial`, with every
+    /// subsequent restart doubling the delay, up to `max`.  Once the
+    /// worker failed more than `max_restarts` times in a row the
+    /// failure is escalated to the parent instead.
+    Backoff {
+        /// The delay before the first restart
+        initial: Duration,
+        /// The upper bound for the restart delay
+        max: Duration,
+        /// The number of restarts after which to give up
+        max_restarts: u32,
+    },
+    /// Never restart the worker and notify the parent instead
+    ///
+    /// The parent is the worker whose context started the supervised
+    /// worker.  It receives a [`WorkerFailed`] message.
+    Escalate,
+    /// Shut down the whole node
+    StopNode,
+}
+
+impl RestartPolicy {
+    /// Return the delay before the next restart
+    ///
+    /// Returns `None` if the worker should not be restarted again.
+    fn restart_delay(&self, restarts: u32) -> Option<Duration> {
+        match *self {
+            Self::OneForOne { max_restarts } if restarts < max_restarts => {
+                Some(Duration::from_secs(0))
+            }
+            Self::Backoff {
+                initial,
+                max,
+                max_restarts,
+            } if restarts < max_restarts => Some(backoff(initial, max, restarts)),
+            _ => None,
+        }
+    }
+}
+
+/// A notification sent to a parent worker when a supervised worker failed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WorkerFailed {
+    /// The primary address of the failed worker
+    pub address: Address,
+    /// A description of the last failure
+    pub reason: String,
+}
+
+/// Return the number of restarts that count towards the restart limit
+///
+/// A worker that failed after running for at least [`STABLE_PERIOD`]
+/// starts counting again.
+fn recent_restarts(restarts: u32, uptime: Duration) -> u32 {
+    if uptime >= STABLE_PERIOD {
+        0
+    } else {
+        restarts
+    }
+}
+
+/// Calculate the delay before the next restart of a backoff policy
+fn backoff(initial: Duration, max: Duration, restarts: u32) -> Duration {
+    2u32.checked_pow(restarts)
+        .and_then(|factor| initial.checked_mul(factor))
+        .map_or(max, |delay| delay.min(max))
+}
+
+/// A future wrapper which turns a panic into an `Err`
+///
+/// This is a minimal version of `futures::FutureExt::catch_unwind`
+struct CatchUnwind<F: Future>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = thread::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let fut = self.0.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| fut.poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(out)) => Poll::Ready(Ok(out)),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// Notify the parent worker that a worker has failed for good
+async fn escalate(ctx: &Context, parent: &Address, reason: String) {
+    let addr = ctx.primary_address();
+    debug!("Escalating failure of worker {} to {}", addr, parent);
+
+    let msg = WorkerFailed {
+        address: addr.clone(),
+        reason,
+    };
+    if let Err(e) = ctx.send_message(parent.clone(), msg).await {
+        error!(
+            "Failed to notify {} of failed worker {}: {}",
+            parent, addr, e
+        );
+    }
+}
+
+struct Supervisor<W, M, F>
+where
+    W: Worker<Context = Context, Message = M>,
+    M: Message + Send + 'static,
+    F: Fn() -> W + Send + 'static,
+{
+    factory: F,
+    policy: RestartPolicy,
+    parent: Address,
+    ctx: Context,
+}
+
+impl<W, M, F> Supervisor<W, M, F>
+where
+    W: Worker<Context = Context, Message = M>,
+    M: Message + Send + 'static,
+    F: Fn() -> W + Send + 'static,
+{
+    async fn run(mut self) {
+        let addr = self.ctx.primary_address();
+        let mut restarts = 0;
+
+        loop {
+            let mut worker = (self.factory)();
+            let started = Instant::now();
+            let run = Relay::<W, M>::run_worker(&mut worker, &mut self.ctx, true);
+
+            let reason = match CatchUnwind(Box::pin(run)).await {
+                // The mailbox was closed, so the worker was stopped
+                Ok(Ok(())) => break,
+                Ok(Err(e)) => format!("{}", e),
+                Err(_) => "worker panicked".to_string(),
+            };
+            error!("Supervised worker {} failed: {}", addr, reason);
+
+            restarts = recent_restarts(restarts, started.elapsed());
+            match self.policy.restart_delay(restarts) {
+                Some(delay) => time::sleep(delay).await,
+                None if self.policy == RestartPolicy::StopNode => {
+                    if let Err(e) = self.ctx.stop().await {
+                        error!("Failed to stop node after {} failed: {}", addr, e);
+                    }
+                    break;
+                }
+                None => {
+                    escalate(&self.ctx, &self.parent, reason).await;
+                    break;
+                }
+            }
+
+            restarts += 1;
+            debug!(
+                "Restarting supervised worker {} (restart #{})",
+                addr, restarts
+            );
+        }
+    }
+}
+
+/// Build and spawn a new supervised worker, returning a send handle to it
+pub(crate) fn build<W, M, F>(
+    rt: &Runtime,
+    factory: F,
+    policy: RestartPolicy,
+    parent: Address,
+    ctx: Context,
+) -> MailboxSender
+where
+    W: Worker<Context = Context, Message = M>,
+    M: Message + Send + 'static,
+    F: Fn() -> W + Send + 'static,
+{
+    let sender = ctx.mailbox.sender();
+    let supervisor = Supervisor {
+        factory,
+        policy,
+        parent,
+        ctx,
+    };
+
+    rt.spawn(supervisor.run());
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::test_node;
+    use ockam_core::{async_trait::async_trait, Result, Routed};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let max = 100 * MS;
+        assert_eq!(backoff(10 * MS, max, 0), 10 * MS);
+        assert_eq!(backoff(10 * MS, max, 1), 20 * MS);
+        assert_eq!(backoff(10 * MS, max, 3), 80 * MS);
+        assert_eq!(backoff(10 * MS, max, 4), max);
+        assert_eq!(backoff(10 * MS, max, 40), max);
+        assert_eq!(backoff(Duration::from_secs(u64::MAX), max, 1), max);
+    }
+
+    #[test]
+    fn restart_delay_per_policy() {
+        let one_for_one = RestartPolicy::OneForOne { max_restarts: 2 };
+        assert_eq!(one_for_one.restart_delay(0), Some(Duration::from_secs(0)));
+        assert_eq!(one_for_one.restart_delay(1), Some(Duration::from_secs(0)));
+        assert_eq!(one_for_one.restart_delay(2), None);
+
+        let backoff = RestartPolicy::Backoff {
+            initial: 10 * MS,
+            max: 15 * MS,
+            max_restarts: 2,
+        };
+        assert_eq!(backoff.restart_delay(0), Some(10 * MS));
+        assert_eq!(backoff.restart_delay(1), Some(15 * MS));
+        assert_eq!(backoff.restart_delay(2), None);
+
+        assert_eq!(RestartPolicy::Escalate.restart_delay(0), None);
+        assert_eq!(RestartPolicy::StopNode.restart_delay(0), None);
+    }
+
+    #[test]
+    fn stable_workers_reset_restarts() {
+        assert_eq!(recent_restarts(3, Duration::from_secs(0)), 3);
+        assert_eq!(recent_restarts(3, STABLE_PERIOD - MS), 3);
+        assert_eq!(recent_restarts(3, STABLE_PERIOD), 0);
+    }
+
+    /// Fails to handle `0`, and echoes all other numbers to `app`
+    struct Flaky {
+        panic: bool,
+    }
+
+    #[async_trait]
+    impl Worker for Flaky {
+        type Context = Context;
+        type Message = u64;
+
+        async fn handle_message(&mut self, ctx: &mut Context, msg: Routed<u64>) -> Result<()> {
+            match msg.take() {
+                0 if self.panic => panic!("flaky worker panicked"),
+                0 => Err(crate::error::Error::FailedLoadData.into()),
+                n => ctx.send_message("app", n).await,
+            }
+        }
+    }
+
+    /// Start a supervised flaky worker, counting its instances
+    async fn start_flaky(
+        ctx: &Context,
+        policy: RestartPolicy,
+        panic: bool,
+    ) -> Result<Arc<AtomicUsize>> {
+        let instances = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&instances);
+        ctx.start_supervised_worker(
+            "flaky",
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Flaky { panic }
+            },
+            policy,
+        )
+        .await?;
+        Ok(instances)
+    }
+
+    #[test]
+    fn restarts_failed_workers() {
+        test_node(|mut ctx| async move {
+            let policy = RestartPolicy::OneForOne { max_restarts: 2 };
+            let instances = start_flaky(&ctx, policy, true).await?;
+
+            // The message queued behind the panic reaches the new instance
+            ctx.send_message("flaky", 0u64).await?;
+            ctx.send_message("flaky", 1u64).await?;
+            assert_eq!(ctx.receive::<u64>().await?.take().take(), 1);
+            assert_eq!(instances.load(Ordering::SeqCst), 2);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn escalates_after_max_restarts() {
+        test_node(|mut ctx| async move {
+            let policy = RestartPolicy::OneForOne { max_restarts: 2 };
+            let instances = start_flaky(&ctx, policy, false).await?;
+
+            for _ in 0..3 {
+                ctx.send_message("flaky", 0u64).await?;
+            }
+            let failed = ctx.receive::<WorkerFailed>().await?.take().take();
+            assert_eq!(failed.address, "flaky".into());
+            assert_eq!(instances.load(Ordering::SeqCst), 3);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn escalates_without_restarting() {
+        test_node(|mut ctx| async move {
+            let instances = start_flaky(&ctx, RestartPolicy::Escalate, false).await?;
+
+            ctx.send_message("flaky", 0u64).await?;
+            let failed = ctx.receive::<WorkerFailed>().await?.take().take();
+            assert_eq!(failed.address, "flaky".into());
+            assert_eq!(instances.load(Ordering::SeqCst), 1);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,94 @@
+//! Delayed and periodic message delivery
+//!
+//! Timers are started from a worker [`Context`], and deliver their
+//! message through the same routing path as
+//! [`Context::send_message`].  A timer is tied to the worker that
+//! started it, and stops once that worker's mailbox was closed.
+//!
+//! [`Context`]: crate::Context
+//! [`Context::send_message`]: crate::Context::send_message
+
+use crate::{context, MailboxSender, NodeMessage};
+use ockam_core::{Address, Route, TraceContext};
+use std::time::Duration;
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::Sender,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+/// A handle to a running timer
+///
+/// Dropping this handle does not stop the timer.  Use
+/// [`TimerHandle::cancel`] to stop it before its owning worker is
+/// stopped.
+#[derive(Debug)]
+pub struct TimerHandle {
+    task: JoinHandle<()>,
+}
+
+impl TimerHandle {
+    /// Stop the timer from delivering any further messages
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
+}
+
+/// The message that a timer delivers
+pub(crate) struct TimerMessage {
+    pub(crate) route: Route,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) message_type: Option<String>,
+    /// The trace of the message that started the timer
+    pub(crate) trace: TraceContext,
+}
+
+/// Spawn a timer task
+///
+/// The message is first delivered after `delay`, and then every
+/// `period`, if one is given.  `owner` is the mailbox of the worker
+/// that started the timer.
+pub(crate) fn start(
+    rt: &Runtime,
+    router: Sender<NodeMessage>,
+    owner: MailboxSender,
+    from: Address,
+    msg: TimerMessage,
+    delay: Duration,
+    period: Option<Duration>,
+) -> TimerHandle {
+    let task = rt.spawn(async move {
+        let mut deadline = Instant::now() + delay;
+
+        loop {
+            time::sleep_until(deadline).await;
+
+            // Timers stop together with the worker that started them
+            if owner.is_closed() {
+                trace!("Stopping timer of worker {}", from);
+                break;
+            }
+
+            if let Err(e) = context::deliver(
+                &router,
+                msg.route.clone(),
+                msg.payload.clone(),
+                msg.message_type.clone(),
+                msg.trace,
+                from.clone(),
+            )
+            .await
+            {
+                warn!("Failed to deliver timer message of worker {}: {}", from, e);
+            }
+
+            match period {
+                Some(period) => deadline += period,
+                None => break,
+            }
+        }
+    });
+
+    TimerHandle { task }
+}
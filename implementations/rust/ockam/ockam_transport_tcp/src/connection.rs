@@ -0,0 +1,230 @@
+//! Outbound connection state and reconnect handling
+//!
+//! The two workers of a pair share the write half of their stream.
+//! When the receiving worker of an outbound pair loses its
+//! connection, it takes the write half away from the sending worker,
+//! which buffers outgoing messages until the connection has been
+//! re-established.  Connection state changes are published on the
+//! [`CONNECTION_EVENTS`] topic.
+
+use crate::{FrameOptions, KeepaliveOptions, TcpError};
+use ockam::Address;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+use tokio::{net::tcp::OwnedWriteHalf, sync::Mutex};
+
+/// The topic on which [`TcpConnectionEvent`]s are published
+pub const CONNECTION_EVENTS: &str = "ockam.tcp.connection";
+
+/// Determine how a lost outbound connection is re-established
+///
+/// The first reconnect attempt is delayed by `initial`, with every
+/// subsequent attempt doubling the delay, up to `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// The delay before the first reconnect attempt
+    pub initial: Duration,
+    /// The upper bound for the delay between attempts
+    pub max: Duration,
+    /// The number of attempts after which to give up, or `None` to
+    /// never give up
+    pub max_attempts: Option<u32>,
+    /// The number of outgoing messages to buffer while reconnecting
+    ///
+    /// Once the buffer is full, further messages are rejected with
+    /// [`TcpError::ReconnectBufferFull`](crate::TcpError::ReconnectBufferFull).
+    pub buffer_size: usize,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            max_attempts: Some(10),
+            buffer_size: 1024,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// A policy that never reconnects
+    pub fn never() -> Self {
+        Self {
+            max_attempts: Some(0),
+            buffer_size: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Return the delay before a reconnect attempt
+    ///
+    /// Returns `None` if no more attempts should be made.
+    pub(crate) fn delay(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.map_or(false, |max| attempt >= max) {
+            return None;
+        }
+        Some(
+            2u32.checked_pow(attempt)
+                .and_then(|factor| self.initial.checked_mul(factor))
+                .map_or(self.max, |delay| delay.min(self.max)),
+        )
+    }
+}
+
+/// The options of an outbound connection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// How the connection is re-established once it is lost
+    pub reconnect: ReconnectPolicy,
+    /// The limits for the frames exchanged on the connection
+    pub framing: FrameOptions,
+    /// The heartbeat and idle timeout settings of the connection
+    pub keepalive: KeepaliveOptions,
+}
+
+/// The state of a TCP connection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection was established or re-established
+    Connected,
+    /// The connection was lost
+    Disconnected,
+    /// A reconnect attempt is about to be made
+    Reconnecting {
+        /// The number of this attempt, starting at 1
+        attempt: u32,
+    },
+    /// The connection was lost for good, and the worker pair was
+    /// stopped
+    Failed,
+}
+
+/// A change of the state of a TCP connection
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TcpConnectionEvent {
+    /// The address of the peer
+    pub peer: Address,
+    /// The new state of the connection
+    pub state: ConnectionState,
+}
+
+/// The write half of a connection, shared by both workers of a pair
+pub(crate) type SharedConnection = Arc<Mutex<Connection>>;
+
+pub(crate) struct Connection {
+    /// The write half of the stream, or `None` while reconnecting
+    pub(crate) tx: Option<OwnedWriteHalf>,
+    /// Messages waiting to be sent once the connection is back
+    pub(crate) buffer: VecDeque<Vec<u8>>,
+    /// Whether the peer understands heartbeats
+    pub(crate) heartbeats: bool,
+    /// The limits for outgoing frames, which the peer accepts
+    pub(crate) framing: FrameOptions,
+    buffer_size: usize,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        tx: OwnedWriteHalf,
+        buffer_size: usize,
+        heartbeats: bool,
+        framing: FrameOptions,
+    ) -> SharedConnection {
+        Arc::new(Mutex::new(Self {
+            tx: Some(tx),
+            buffer: VecDeque::new(),
+            heartbeats,
+            framing,
+            buffer_size,
+        }))
+    }
+
+    /// Keep a message until the connection has been re-established
+    ///
+    /// Fails with `ConnectionDrop` if the connection is never
+    /// re-established, and with `ReconnectBufferFull` if too many
+    /// messages are waiting already.  The message is dropped in both
+    /// cases.
+    pub(crate) fn buffer(&mut self, frame: Vec<u8>) -> Result<(), TcpError> {
+        if self.buffer_size == 0 {
+            return Err(TcpError::ConnectionDrop);
+        }
+        if self.buffer.len() >= self.buffer_size {
+            return Err(TcpError::ReconnectBufferFull);
+        }
+        self.buffer.push_back(frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_delays() {
+        let ms = Duration::from_millis;
+        let policy = ReconnectPolicy {
+            initial: ms(100),
+            max: ms(1000),
+            max_attempts: Some(6),
+            buffer_size: 0,
+        };
+        let table = [
+            (0, Some(ms(100))),
+            (1, Some(ms(200))),
+            (2, Some(ms(400))),
+            (3, Some(ms(800))),
+            // Capped at the maximum delay
+            (4, Some(ms(1000))),
+            (5, Some(ms(1000))),
+            // Out of attempts
+            (6, None),
+            (7, None),
+        ];
+
+        for &(attempt, expected) in table.iter() {
+            assert_eq!(policy.delay(attempt), expected, "attempt {}", attempt);
+        }
+    }
+
+    #[test]
+    fn reconnect_delays_without_limit() {
+        let policy = ReconnectPolicy {
+            max_attempts: None,
+            ..Default::default()
+        };
+
+        // Large attempts overflow the factor, and stay at the maximum
+        for &attempt in [20, 31, 32, 64, u32::MAX].iter() {
+            assert_eq!(policy.delay(attempt), Some(policy.max));
+        }
+        assert_eq!(ReconnectPolicy::never().delay(0), None);
+    }
+
+    #[test]
+    fn reject_messages_that_cannot_be_buffered() {
+        crate::test_node(|_| async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (_, tx) = stream.into_split();
+
+            // Inbound connections are never re-established
+            let conn = Connection::new(tx, 0, false, FrameOptions::default());
+            let mut conn = conn.lock().await;
+            let res = conn.buffer(vec![1]);
+            assert!(matches!(res, Err(TcpError::ConnectionDrop)));
+
+            conn.buffer_size = 2;
+            assert!(conn.buffer(vec![1]).is_ok());
+            assert!(conn.buffer(vec![2]).is_ok());
+            let res = conn.buffer(vec![3]);
+            assert!(matches!(res, Err(TcpError::ReconnectBufferFull)));
+            assert_eq!(conn.buffer, vec![vec![1], vec![2]]);
+            Ok(())
+        })
+    }
+}
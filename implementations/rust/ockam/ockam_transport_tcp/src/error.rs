@@ -23,6 +23,24 @@ pub enum TcpError {
     PeerBusy,
     /// A generic I/O failure
     GenericIo,
+    /// A peer address could not be parsed
+    InvalidPeer,
+    /// The host name of a peer could not be resolved
+    ResolveFailed,
+    /// A received frame exceeds the maximum frame size
+    FrameTooLarge,
+    /// A message exceeds the maximum message size
+    MessageTooLarge,
+    /// The peer doesn't speak the Ockam TCP protocol
+    IncompatiblePeer,
+    /// The peer uses a protocol version that isn't supported
+    UnsupportedVersion,
+    /// The peer didn't send its preamble in time
+    HandshakeTimeout,
+    /// The connection was silent for longer than the idle timeout
+    IdleTimeout,
+    /// Too many messages are waiting for a connection to be re-established
+    ReconnectBufferFull,
 }
 
 impl TcpError {
@@ -41,9 +59,9 @@ impl From<TcpError> for Error {
 impl From<std::io::Error> for TcpError {
     fn from(e: std::io::Error) -> Self {
         use std::io::ErrorKind::*;
-        dbg!();
         match e.kind() {
             ConnectionRefused => Self::PeerNotFound,
+            UnexpectedEof | ConnectionReset | ConnectionAborted => Self::ConnectionDrop,
             _ => Self::GenericIo,
         }
     }
@@ -0,0 +1,271 @@
+//! Message framing on TCP streams
+//!
+//! Every message is sent as one or more frames.  A frame starts with
+//! a one byte flags field, followed by the length of its payload as a
+//! big endian `u32`.  Messages that are larger than the maximum frame
+//! size are split into chunks, where every frame but the last one has
+//! the [`MORE`] flag set.  Empty frames with the [`HEARTBEAT`] flag
+//! set keep idle connections alive, and are never part of a message.
+//!
+//! ```text
+//! +-------+------------+-----------------+
+//! | flags | length u32 | payload ...     |
+//! +-------+------------+-----------------+
+//! ```
+
+use crate::TcpError;
+use std::{future::Future, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time,
+};
+
+/// The size of a frame header
+pub(crate) const HEADER_LEN: usize = 5;
+
+/// The message continues in the next frame
+const MORE: u8 = 0x01;
+
+/// The frame is a heartbeat
+const HEARTBEAT: u8 = 0x02;
+
+/// A complete heartbeat frame
+pub(crate) const HEARTBEAT_FRAME: [u8; HEADER_LEN] = [HEARTBEAT, 0, 0, 0, 0];
+
+/// Limits for the frames exchanged on a connection
+///
+/// These are the limits of the frames and messages that are received.
+/// Both ends announce them when connecting, and only send frames and
+/// messages up to the smaller of both limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameOptions {
+    /// The largest payload of a single frame
+    pub max_frame_size: u32,
+    /// The largest message that can be sent or received
+    pub max_message_size: usize,
+}
+
+impl Default for FrameOptions {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 64 * 1024,
+            max_message_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Encode a message into a sequence of frames
+pub(crate) fn encode(msg: &[u8], opts: &FrameOptions) -> Result<Vec<u8>, TcpError> {
+    if msg.len() > opts.max_message_size {
+        return Err(TcpError::MessageTooLarge);
+    }
+
+    let chunk_size = opts.max_frame_size.max(1) as usize;
+    let frames = msg.len() / chunk_size + 1;
+    let mut buf = Vec::with_capacity(msg.len() + frames * HEADER_LEN);
+
+    // Empty messages are sent as a single empty frame
+    let mut offset = 0;
+    loop {
+        let end = msg.len().min(offset + chunk_size);
+        let flags = if end < msg.len() { MORE } else { 0 };
+
+        buf.push(flags);
+        buf.extend_from_slice(&((end - offset) as u32).to_be_bytes());
+        buf.extend_from_slice(&msg[offset..end]);
+
+        if end == msg.len() {
+            return Ok(buf);
+        }
+        offset = end;
+    }
+}
+
+async fn read_header<R>(rx: &mut R) -> Result<(u8, u32), TcpError>
+where
+    R: AsyncRead + Unpin,
+{
+    let flags = rx.read_u8().await?;
+    let len = rx.read_u32().await?;
+    Ok((flags, len))
+}
+
+/// Fail a read that takes longer than `idle_timeout`
+async fn idle<F, T>(idle_timeout: Option<Duration>, read: F) -> Result<T, TcpError>
+where
+    F: Future<Output = Result<T, TcpError>>,
+{
+    match idle_timeout {
+        Some(timeout) => time::timeout(timeout, read)
+            .await
+            .map_err(|_| TcpError::IdleTimeout)?,
+        None => read.await,
+    }
+}
+
+/// Read the frames of a single message from a stream
+///
+/// Returns the message, together with the number of bytes that were
+/// read for it, including the frame headers and heartbeats.  A frame
+/// or message that exceeds the limits is an error, after which the
+/// stream can't be used anymore.  So is a stream that stays silent
+/// for longer than `idle_timeout`, whether between frames or in the
+/// middle of one.
+pub(crate) async fn read_message<R>(
+    rx: &mut R,
+    opts: &FrameOptions,
+    idle_timeout: Option<Duration>,
+) -> Result<(Vec<u8>, usize), TcpError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut msg = Vec::new();
+    let mut read = 0;
+
+    loop {
+        let (flags, len) = idle(idle_timeout, read_header(rx)).await?;
+        trace!("Received frame header for {} bytes", len);
+
+        if flags & HEARTBEAT != 0 {
+            if len != 0 {
+                return Err(TcpError::RecvBadMessage);
+            }
+            read += HEADER_LEN;
+            continue;
+        }
+
+        if len > opts.max_frame_size {
+            return Err(TcpError::FrameTooLarge);
+        }
+        let start = msg.len();
+        if start + len as usize > opts.max_message_size {
+            return Err(TcpError::MessageTooLarge);
+        }
+
+        msg.resize(start + len as usize, 0);
+        idle(idle_timeout, async {
+            rx.read_exact(&mut msg[start..]).await?;
+            Ok(())
+        })
+        .await?;
+        read += HEADER_LEN + len as usize;
+
+        if flags & MORE == 0 {
+            return Ok((msg, read));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_millis(50));
+
+    fn small() -> FrameOptions {
+        FrameOptions {
+            max_frame_size: 4,
+            max_message_size: 16,
+        }
+    }
+
+    async fn read_frames(frames: &[u8], opts: &FrameOptions) -> Result<(Vec<u8>, usize), TcpError> {
+        let (mut tx, mut rx) = duplex(1024);
+        tx.write_all(frames).await.unwrap();
+        read_message(&mut rx, opts, TIMEOUT).await
+    }
+
+    #[tokio::test]
+    async fn chunks_large_messages() {
+        let msg = b"0123456789";
+        let buf = encode(msg, &small()).unwrap();
+
+        // Two full frames with the MORE flag, and the rest
+        assert_eq!(buf.len(), msg.len() + 3 * HEADER_LEN);
+        assert_eq!(&buf[..HEADER_LEN], &[MORE, 0, 0, 0, 4]);
+        assert_eq!(&buf[9..9 + HEADER_LEN], &[MORE, 0, 0, 0, 4]);
+        assert_eq!(&buf[18..18 + HEADER_LEN], &[0, 0, 0, 0, 2]);
+
+        let (read, len) = read_frames(&buf, &small()).await.unwrap();
+        assert_eq!(read, msg);
+        assert_eq!(len, buf.len());
+    }
+
+    #[tokio::test]
+    async fn empty_message() {
+        let buf = encode(&[], &small()).unwrap();
+        assert_eq!(buf, vec![0, 0, 0, 0, 0]);
+
+        let (read, len) = read_frames(&buf, &small()).await.unwrap();
+        assert!(read.is_empty());
+        assert_eq!(len, HEADER_LEN);
+    }
+
+    #[tokio::test]
+    async fn rejects_large_frames() {
+        let res = read_frames(&[0, 0, 0, 0, 5, 1, 2, 3, 4, 5], &small()).await;
+        assert!(matches!(res, Err(TcpError::FrameTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn rejects_large_messages() {
+        let msg = [0; 17];
+        assert!(matches!(
+            encode(&msg, &small()),
+            Err(TcpError::MessageTooLarge)
+        ));
+
+        // Every frame fits, but the message doesn't
+        let opts = FrameOptions {
+            max_message_size: 32,
+            ..small()
+        };
+        let buf = encode(&msg, &opts).unwrap();
+        let res = read_frames(&buf, &small()).await;
+        assert!(matches!(res, Err(TcpError::MessageTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn skips_heartbeats() {
+        let mut buf = HEARTBEAT_FRAME.to_vec();
+        buf.extend(encode(b"hello", &small()).unwrap());
+        buf.extend_from_slice(&HEARTBEAT_FRAME);
+
+        // Heartbeats may also arrive between the frames of a message
+        let mut frames = encode(b"hello", &small()).unwrap();
+        frames.splice(
+            HEADER_LEN + 4..HEADER_LEN + 4,
+            HEARTBEAT_FRAME.iter().copied(),
+        );
+
+        let (read, len) = read_frames(&buf, &small()).await.unwrap();
+        assert_eq!(read, b"hello");
+        assert_eq!(len, HEADER_LEN + 2 * HEADER_LEN + 5);
+
+        let (read, len) = read_frames(&frames, &small()).await.unwrap();
+        assert_eq!(read, b"hello");
+        assert_eq!(len, frames.len());
+    }
+
+    #[tokio::test]
+    async fn rejects_heartbeats_with_payload() {
+        let res = read_frames(&[HEARTBEAT, 0, 0, 0, 1, 0], &small()).await;
+        assert!(matches!(res, Err(TcpError::RecvBadMessage)));
+    }
+
+    #[tokio::test]
+    async fn times_out_between_frames() {
+        let (_tx, mut rx) = duplex(1024);
+        let res = read_message(&mut rx, &small(), TIMEOUT).await;
+        assert!(matches!(res, Err(TcpError::IdleTimeout)));
+    }
+
+    #[tokio::test]
+    async fn times_out_within_frames() {
+        let (mut tx, mut rx) = duplex(1024);
+        tx.write_all(&[0, 0, 0, 0, 4, 1, 2]).await.unwrap();
+        let res = read_message(&mut rx, &small(), TIMEOUT).await;
+        assert!(matches!(res, Err(TcpError::IdleTimeout)));
+    }
+}
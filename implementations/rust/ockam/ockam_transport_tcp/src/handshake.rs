@@ -0,0 +1,287 @@
+//! Connection preamble
+//!
+//! Both ends of a new connection send a preamble before any frames
+//! are exchanged:
+//!
+//! ```text
+//! +--------------+---------+------------------+----------------+------------------+
+//! | magic "OCKM" | version | capabilities u32 | max frame u32  | max message u32  |
+//! +--------------+---------+------------------+----------------+------------------+
+//! ```
+//!
+//! A connection uses the lower of the two protocol versions, and only
+//! the capabilities that both ends support.  New framing or
+//! compression features are added as capability flags, so that they
+//! are only used between peers that understand them.  Each end only
+//! sends frames and messages up to the smaller of the two limits, so
+//! that the other end never has to reject them.  Peers that send a
+//! different magic, a protocol version that isn't supported anymore,
+//! or limits of zero, are disconnected.
+
+use crate::{FrameOptions, TcpError, TcpPeer};
+use std::convert::TryFrom;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+/// The magic bytes starting every connection
+const MAGIC: [u8; 4] = *b"OCKM";
+
+/// The protocol version spoken by this implementation
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The oldest protocol version this implementation accepts
+const MIN_PROTOCOL_VERSION: u8 = 1;
+
+const PREAMBLE_LEN: usize = 17;
+
+/// How long to wait for the preamble of a peer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A set of optional protocol features
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// No optional features
+    pub const NONE: Self = Self(0);
+
+    /// Heartbeat frames are sent on idle connections
+    pub const HEARTBEATS: Self = Self(0x01);
+
+    /// The features supported by this implementation
+    pub const SUPPORTED: Self = Self::HEARTBEATS;
+
+    /// Create a set of features from its wire representation
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Return the wire representation of this set
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Check whether all features of `other` are in this set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Return the features that are in both sets
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// The protocol negotiated for a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Protocol {
+    /// The protocol version used on the connection
+    pub version: u8,
+    /// The optional features both ends support
+    pub capabilities: Capabilities,
+    /// The limits for the frames sent on the connection, which both
+    /// ends accept
+    pub framing: FrameOptions,
+}
+
+/// Exchange preambles with a peer and negotiate a protocol
+///
+/// `framing` are the limits of the frames this end accepts.  The
+/// stream is shut down if the peer is incompatible.
+pub(crate) async fn handshake(
+    stream: &mut TcpStream,
+    peer: &TcpPeer,
+    framing: &FrameOptions,
+) -> Result<Protocol, TcpError> {
+    let res = match time::timeout(HANDSHAKE_TIMEOUT, exchange(stream, framing)).await {
+        Ok(res) => res.and_then(|theirs| negotiate(theirs, framing)),
+        Err(_) => Err(TcpError::HandshakeTimeout),
+    };
+
+    if let Err(e) = res {
+        warn!("Rejecting connection with TCP peer {}: {:?}", peer, e);
+        let _ = stream.shutdown().await;
+    }
+    res
+}
+
+fn preamble(framing: &FrameOptions) -> [u8; PREAMBLE_LEN] {
+    // Message sizes beyond 4 GiB are announced as 4 GiB
+    let max_message_size = u32::try_from(framing.max_message_size).unwrap_or(u32::MAX);
+
+    let mut preamble = [0; PREAMBLE_LEN];
+    preamble[..4].copy_from_slice(&MAGIC);
+    preamble[4] = PROTOCOL_VERSION;
+    preamble[5..9].copy_from_slice(&Capabilities::SUPPORTED.bits().to_be_bytes());
+    preamble[9..13].copy_from_slice(&framing.max_frame_size.to_be_bytes());
+    preamble[13..].copy_from_slice(&max_message_size.to_be_bytes());
+    preamble
+}
+
+/// Send our preamble, and read the one of the peer
+async fn exchange(
+    stream: &mut TcpStream,
+    framing: &FrameOptions,
+) -> Result<[u8; PREAMBLE_LEN], TcpError> {
+    stream.write_all(&preamble(framing)).await?;
+
+    let mut theirs = [0; PREAMBLE_LEN];
+    stream.read_exact(&mut theirs).await?;
+    Ok(theirs)
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(buf);
+    u32::from_be_bytes(bytes)
+}
+
+fn negotiate(theirs: [u8; PREAMBLE_LEN], framing: &FrameOptions) -> Result<Protocol, TcpError> {
+    if theirs[..4] != MAGIC {
+        return Err(TcpError::IncompatiblePeer);
+    }
+
+    let version = theirs[4];
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(TcpError::UnsupportedVersion);
+    }
+
+    let capabilities = Capabilities::from_bits(read_u32(&theirs[5..9]));
+
+    let max_frame_size = read_u32(&theirs[9..13]);
+    let max_message_size = read_u32(&theirs[13..]) as usize;
+    if max_frame_size == 0 || max_message_size == 0 {
+        return Err(TcpError::IncompatiblePeer);
+    }
+
+    Ok(Protocol {
+        version: version.min(PROTOCOL_VERSION),
+        capabilities: Capabilities::SUPPORTED.intersection(capabilities),
+        framing: FrameOptions {
+            max_frame_size: framing.max_frame_size.min(max_frame_size),
+            max_message_size: framing.max_message_size.min(max_message_size),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn theirs(magic: &[u8; 4], version: u8, capabilities: u32) -> [u8; PREAMBLE_LEN] {
+        let mut theirs = preamble(&FrameOptions::default());
+        theirs[..4].copy_from_slice(magic);
+        theirs[4] = version;
+        theirs[5..9].copy_from_slice(&capabilities.to_be_bytes());
+        theirs
+    }
+
+    #[test]
+    fn negotiate_protocol() {
+        let all = Capabilities::SUPPORTED.bits();
+        let table = [
+            // Same version and capabilities
+            (PROTOCOL_VERSION, all, PROTOCOL_VERSION, all),
+            // Newer peers are talked to in our version
+            (PROTOCOL_VERSION + 1, all, PROTOCOL_VERSION, all),
+            // Peers without optional features
+            (PROTOCOL_VERSION, 0, PROTOCOL_VERSION, 0),
+            // Features we don't know about are never used
+            (PROTOCOL_VERSION, 0xffff_ffff, PROTOCOL_VERSION, all),
+            (PROTOCOL_VERSION, 0x8000_0000, PROTOCOL_VERSION, 0),
+        ];
+
+        let framing = FrameOptions::default();
+        for &(version, capabilities, expected_version, expected) in table.iter() {
+            let protocol = negotiate(theirs(&MAGIC, version, capabilities), &framing).unwrap();
+            assert_eq!(protocol.version, expected_version);
+            assert_eq!(protocol.capabilities, Capabilities::from_bits(expected));
+            assert_eq!(protocol.framing, framing);
+        }
+    }
+
+    #[test]
+    fn negotiate_frame_limits() {
+        let ours = FrameOptions {
+            max_frame_size: 1024,
+            max_message_size: 4096,
+        };
+        let table = [
+            // Same limits
+            ((1024, 4096), (1024, 4096)),
+            // Each limit is the smaller one of both ends
+            ((512, 8192), (512, 4096)),
+            ((2048, 2048), (1024, 2048)),
+            ((1, 1), (1, 1)),
+            ((u32::MAX, u32::MAX), (1024, 4096)),
+        ];
+
+        for &((frame, message), (expected_frame, expected_message)) in table.iter() {
+            let mut theirs = preamble(&ours);
+            theirs[9..13].copy_from_slice(&u32::to_be_bytes(frame));
+            theirs[13..].copy_from_slice(&u32::to_be_bytes(message));
+
+            let protocol = negotiate(theirs, &ours).unwrap();
+            assert_eq!(protocol.framing.max_frame_size, expected_frame);
+            assert_eq!(protocol.framing.max_message_size, expected_message);
+        }
+    }
+
+    #[test]
+    fn announce_frame_limits() {
+        let framing = FrameOptions {
+            max_frame_size: 1024,
+            max_message_size: usize::MAX,
+        };
+        let preamble = preamble(&framing);
+        assert_eq!(read_u32(&preamble[9..13]), 1024);
+        assert_eq!(read_u32(&preamble[13..]), u32::MAX);
+    }
+
+    #[test]
+    fn reject_incompatible_peers() {
+        let all = Capabilities::SUPPORTED.bits();
+        let table = [
+            // Not an Ockam peer at all
+            (*b"GET ", PROTOCOL_VERSION, TcpError::IncompatiblePeer),
+            (*b"OCKm", PROTOCOL_VERSION, TcpError::IncompatiblePeer),
+            ([0; 4], 0, TcpError::IncompatiblePeer),
+            // Peers that are too old
+            (
+                MAGIC,
+                MIN_PROTOCOL_VERSION - 1,
+                TcpError::UnsupportedVersion,
+            ),
+        ];
+
+        let framing = FrameOptions::default();
+        for &(magic, version, expected) in table.iter() {
+            let res = negotiate(theirs(&magic, version, all), &framing);
+            assert_eq!(res.map_err(|e| e as u32), Err(expected as u32));
+        }
+
+        // Peers that can't receive anything
+        for &(start, end) in [(9, 13), (13, PREAMBLE_LEN)].iter() {
+            let mut theirs = theirs(&MAGIC, PROTOCOL_VERSION, all);
+            theirs[start..end].copy_from_slice(&[0; 4]);
+            let res = negotiate(theirs, &framing);
+            assert!(matches!(res, Err(TcpError::IncompatiblePeer)));
+        }
+    }
+
+    #[test]
+    fn capability_sets() {
+        let heartbeats = Capabilities::HEARTBEATS;
+        assert!(heartbeats.contains(Capabilities::NONE));
+        assert!(!Capabilities::NONE.contains(heartbeats));
+        assert_eq!(
+            Capabilities::from_bits(0x03).intersection(heartbeats),
+            heartbeats
+        );
+        assert_eq!(Capabilities::from_bits(0x03).bits(), 0x03);
+    }
+}
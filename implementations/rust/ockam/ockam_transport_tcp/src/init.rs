@@ -1,85 +1,151 @@
 use crate::{
     atomic::{self, ArcBool},
-    TcpError, TcpRecvWorker, TcpRouter, TcpSendWorker,
+    connection::Connection,
+    handshake::{self, Capabilities, Protocol},
+    keepalive::{self, KeepaliveOptions},
+    listener::ConnectionSlot,
+    receiver::Receive,
+    resolver, ConnectionOptions, FrameOptions, ReconnectPolicy, TcpPeer, TcpRecvWorker, TcpRouter,
+    TcpSendWorker,
 };
 use ockam::{Address, Context, Result};
-use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, sync::oneshot};
 
 pub struct WorkerPair {
-    pub(crate) peer: SocketAddr,
+    pub(crate) peer: TcpPeer,
     pub(crate) tx_addr: Address,
     pub(crate) rx_addr: Address,
+    protocol: Protocol,
     run: ArcBool,
 }
 
 impl WorkerPair {
     /// Stop the worker pair
+    ///
+    /// The run flag is cleared first, so that the receiving task does
+    /// not tear the connection down or reconnect while the workers
+    /// are stopped.  Stopping the receiving worker interrupts the
+    /// task, even on an idle connection.
     pub async fn stop(self, ctx: &Context) -> Result<()> {
+        atomic::stop(&self.run);
         ctx.stop_worker(self.tx_addr).await?;
         ctx.stop_worker(self.rx_addr).await?;
-        atomic::stop(&self.run);
         Ok(())
     }
 
-    fn from_peer(addr: &SocketAddr) -> Self {
+    /// Return the protocol negotiated with the peer
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    fn from_peer(addr: &TcpPeer, protocol: Protocol) -> Self {
         Self {
             peer: addr.clone(),
             tx_addr: format!("{}_tx", addr).into(),
             rx_addr: format!("{}_rx", addr).into(),
+            protocol,
             run: atomic::new(true),
         }
     }
 }
 
 impl WorkerPair {
+    /// Start a worker pair for an established connection
+    ///
+    /// The workers are only started once the peer has sent a
+    /// compatible preamble.  Only pairs with a reconnect policy
+    /// re-establish their connection once it is lost, while all other
+    /// pairs are stopped.
     pub(crate) async fn with_stream(
         ctx: &Context,
-        stream: TcpStream,
-        peer: SocketAddr,
+        mut stream: TcpStream,
+        peer: TcpPeer,
+        policy: Option<ReconnectPolicy>,
+        framing: FrameOptions,
+        keepalive: KeepaliveOptions,
+        slot: Option<ConnectionSlot>,
     ) -> Result<Self> {
+        let protocol = handshake::handshake(&mut stream, &peer, &framing).await?;
+        debug!("Negotiated {:?} with TCP peer {}", protocol, peer);
+
         let WorkerPair {
             peer,
             rx_addr,
             tx_addr,
+            protocol,
             run,
-        } = WorkerPair::from_peer(&peer);
+        } = WorkerPair::from_peer(&peer, protocol);
 
         trace!("Creating new worker pair from stream");
 
         // Create two workers based on the split TCP I/O streams
         let (rx, tx) = stream.into_split();
+        let heartbeats = protocol.capabilities.contains(Capabilities::HEARTBEATS);
+        let buffer_size = policy.map_or(0, |p| p.buffer_size);
+        let conn = Connection::new(tx, buffer_size, heartbeats, protocol.framing);
         let sender = TcpSendWorker {
-            tx,
+            conn: conn.clone(),
             peer: peer.clone(),
         };
+        let (stop, stopped) = oneshot::channel();
         let receiver = TcpRecvWorker {
+            run: run.clone(),
+            stop: Some(stop),
+        };
+        let receive = Receive {
+            ctx: ctx.new_context(format!("{}_recv", rx_addr)).await?,
             rx,
             run: run.clone(),
-            peer_addr: format!("1#{}", peer).into(),
+            peer: peer.clone(),
+            peer_addr: peer.address(),
+            rx_addr: rx_addr.clone(),
+            tx_addr: tx_addr.clone(),
+            conn: conn.clone(),
+            policy,
+            framing,
+            keepalive,
+            heartbeats,
+            _slot: slot,
         };
 
         // Derive local worker addresses, and start them
         ctx.start_worker(tx_addr.clone(), sender).await?;
         ctx.start_worker(rx_addr.clone(), receiver).await?;
+        tokio::spawn(receive.run(stopped));
+        if let Some(interval) = keepalive.interval {
+            keepalive::spawn(conn, run.clone(), interval);
+        }
 
         // Return a handle to the worker pair
         Ok(WorkerPair {
             peer,
             rx_addr,
             tx_addr,
+            protocol,
             run,
         })
     }
 
-    async fn start(ctx: &Context, peer: SocketAddr) -> Result<Self> {
+    pub(crate) async fn start(
+        ctx: &Context,
+        peer: TcpPeer,
+        opts: ConnectionOptions,
+    ) -> Result<Self> {
         debug!("Starting worker connection to remote {}", peer);
 
-        // TODO: make i/o errors into ockam_error
-        let stream = TcpStream::connect(peer.clone())
-            .await
-            .map_err(|e| TcpError::from(e))?;
-        Self::with_stream(ctx, stream, peer).await
+        // Host names are resolved again for every new connection,
+        // unless they were resolved recently
+        let stream = resolver::connect(&peer).await?;
+        Self::with_stream(
+            ctx,
+            stream,
+            peer,
+            Some(opts.reconnect),
+            opts.framing,
+            opts.keepalive,
+            None,
+        )
+        .await
     }
 }
 
@@ -87,15 +153,80 @@ impl WorkerPair {
 ///
 /// One worker handles outgoing messages, while another handles
 /// incoming messages.  The local worker address is chosen based on
-/// the peer the worker is meant to be connected to.
-pub async fn start_connection<P>(ctx: &Context, peer: P) -> Result<WorkerPair>
+/// the peer the worker is meant to be connected to.  The connection is
+/// re-established according to `opts` whenever it is lost.
+pub async fn start_connection<P>(
+    ctx: &Context,
+    peer: P,
+    opts: ConnectionOptions,
+) -> Result<WorkerPair>
 where
-    P: Into<SocketAddr>,
+    P: Into<TcpPeer>,
 {
     let router = TcpRouter::register_or_get(ctx).await?;
 
     let peer = peer.into();
-    let pair = WorkerPair::start(ctx, peer).await?;
+    let pair = WorkerPair::start(ctx, peer, opts).await?;
     router.register(&pair).await?;
     Ok(pair)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_node, FrameOptions, ListenerOptions, TcpTransport};
+    use ockam::Route;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::time;
+
+    #[test]
+    fn send_within_limits_of_peer() {
+        test_node(|mut ctx| async move {
+            let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let opts = ListenerOptions {
+                framing: FrameOptions {
+                    max_frame_size: 16,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let listener = TcpTransport::listen(&ctx, addr, opts).await?;
+            let pair = TcpTransport::create(&ctx, listener.local_addr()).await?;
+            assert_eq!(pair.protocol().framing, opts.framing);
+
+            // Larger than a single frame the listener accepts
+            let route = Route::new()
+                .append(pair.peer.address())
+                .append(ctx.primary_address());
+            ctx.send_message(route, "x".repeat(200)).await?;
+
+            let msg = ctx
+                .receive_timeout::<String>(Duration::from_secs(5))
+                .await?;
+            assert_eq!(msg.take().take(), "x".repeat(200));
+            pair.stop(&ctx).await
+        })
+    }
+
+    #[test]
+    fn stop_idle_connection() {
+        test_node(|ctx| async move {
+            let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let listener = TcpTransport::listen(&ctx, addr, ListenerOptions::default()).await?;
+            let pair = TcpTransport::create(&ctx, listener.local_addr()).await?;
+
+            // Nothing is ever sent on the connection
+            time::timeout(Duration::from_secs(5), pair.stop(&ctx))
+                .await
+                .expect("stopping an idle connection must not hang")?;
+
+            // The accepted end is torn down once the peer is gone
+            let mut waited = 0;
+            while listener.connections() > 0 {
+                assert!(waited < 100, "accepted connection was not torn down");
+                time::sleep(Duration::from_millis(50)).await;
+                waited += 1;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,69 @@
+//! Connection heartbeats
+//!
+//! Peers that negotiated the [`Capabilities::HEARTBEATS`] feature
+//! send an empty heartbeat frame whenever the heartbeat interval has
+//! passed.  A receiving worker that doesn't see any frame for longer
+//! than the idle timeout considers its connection dead, which also
+//! catches half-open connections that never return a read error.
+//!
+//! [`Capabilities::HEARTBEATS`]: crate::Capabilities::HEARTBEATS
+
+use crate::{
+    atomic::{self, ArcBool},
+    connection::SharedConnection,
+    framing::HEARTBEAT_FRAME,
+};
+use std::time::Duration;
+use tokio::{io::AsyncWriteExt, time};
+
+/// Heartbeat and idle timeout settings of a connection
+///
+/// The idle timeout should be a multiple of the heartbeat interval of
+/// the peer, so that a single delayed heartbeat doesn't close the
+/// connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepaliveOptions {
+    /// How often to send heartbeats, or `None` to never send them
+    pub interval: Option<Duration>,
+    /// How long a connection may stay silent before it is closed, or
+    /// `None` to keep silent connections open
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for KeepaliveOptions {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(10)),
+            idle_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Send heartbeats on a connection until its worker pair stops
+pub(crate) fn spawn(conn: SharedConnection, run: ArcBool, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = time::interval(interval);
+
+        // The first tick completes right away
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if !atomic::check(&run) {
+                break;
+            }
+
+            // Nothing is sent while reconnecting, or to peers that
+            // don't understand heartbeats
+            let mut conn = conn.lock().await;
+            if !conn.heartbeats {
+                continue;
+            }
+            if let Some(tx) = conn.tx.as_mut() {
+                if let Err(e) = tx.write_all(&HEARTBEAT_FRAME).await {
+                    // The receiving worker notices the lost connection
+                    debug!("Failed to send TCP heartbeat: {}", e);
+                }
+            }
+        }
+    });
+}
@@ -22,42 +22,128 @@
 extern crate tracing;
 
 pub(crate) mod atomic;
+mod connection;
 mod error;
+mod framing;
+mod handshake;
 mod init;
+mod keepalive;
 mod listener;
+mod peer;
 mod receiver;
+mod resolver;
 mod router;
 mod sender;
 
+pub use connection::{
+    ConnectionOptions, ConnectionState, ReconnectPolicy, TcpConnectionEvent, CONNECTION_EVENTS,
+};
 pub use error::TcpError;
+pub use framing::FrameOptions;
+pub use handshake::{Capabilities, Protocol, PROTOCOL_VERSION};
 pub use init::WorkerPair;
+pub use keepalive::KeepaliveOptions;
+pub use listener::{ListenerOptions, TcpListenerHandle};
+pub use peer::TcpPeer;
 pub use receiver::TcpRecvWorker;
-pub use router::{TcpRouter, TcpRouterHandle};
+pub use router::{TcpRouteFailure, TcpRouter, TcpRouterHandle};
 pub use sender::TcpSendWorker;
 
 use ockam::{Context, Result};
 use std::net::SocketAddr;
 
+/// The address type of TCP peers
+pub const TCP: u8 = 1;
+
+/// The scheme of TCP peer addresses, as in `tcp://127.0.0.1:4000`
+pub const TCP_SCHEME: &str = "tcp://";
+
+/// The metric counting bytes sent to each TCP peer
+///
+/// The counters of a peer are removed once its connection is closed.
+pub const BYTES_SENT: &str = "ockam_tcp_bytes_sent_total";
+
+/// The metric counting bytes received from each TCP peer
+///
+/// The counters of a peer are removed once its connection is closed.
+pub const BYTES_RECEIVED: &str = "ockam_tcp_bytes_received_total";
+
 /// An API layer object to control Ockam TCP transports
 pub struct TcpTransport;
 
 impl TcpTransport {
     /// Create a TCP transport and establish an outgoing connection
+    ///
+    /// The peer can be given as a socket address, or as a host name
+    /// and port, such as `"hub.example.com:4000"`.
     pub async fn create<P>(ctx: &Context, peer: P) -> Result<WorkerPair>
     where
-        P: Into<SocketAddr>,
+        P: Into<TcpPeer>,
     {
-        init::start_connection(ctx, peer).await
+        init::start_connection(ctx, peer, ConnectionOptions::default()).await
+    }
+
+    /// Create a TCP transport with custom connection options
+    ///
+    /// Use [`ReconnectPolicy::never`] for connections that should not
+    /// be re-established once they are lost.
+    pub async fn create_with_options<P>(
+        ctx: &Context,
+        peer: P,
+        opts: ConnectionOptions,
+    ) -> Result<WorkerPair>
+    where
+        P: Into<TcpPeer>,
+    {
+        init::start_connection(ctx, peer, opts).await
     }
 
     /// Create a TCP transport and listen for incoming connections
-    pub async fn create_listener<'c, P>(
-        ctx: &'c Context,
+    pub async fn create_listener<P>(
+        ctx: &Context,
         socket_addr: P,
-    ) -> Result<TcpRouterHandle<'c>>
+    ) -> Result<TcpRouterHandle<'_>>
     where
         P: Into<SocketAddr>,
     {
         TcpRouter::bind(ctx, socket_addr).await
     }
+
+    /// Listen for incoming connections with custom options
+    ///
+    /// The returned handle can be used to close the listener again.
+    pub async fn listen<P>(
+        ctx: &Context,
+        socket_addr: P,
+        opts: ListenerOptions,
+    ) -> Result<TcpListenerHandle>
+    where
+        P: Into<SocketAddr>,
+    {
+        let router = TcpRouter::register_or_get(ctx).await?;
+        router.listen(socket_addr, opts).await
+    }
+}
+
+/// Run a test on a fresh node, and stop the node once it is done
+#[cfg(test)]
+pub(crate) fn test_node<F, Fut>(test: F)
+where
+    F: FnOnce(Context) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+{
+    let (ctx, mut exe) = ockam::start_node();
+    let (tx, rx) = std::sync::mpsc::channel();
+    exe.execute(async move {
+        let node = ctx.new_context("test_node").await.unwrap();
+        let res = tokio::spawn(test(ctx)).await;
+        let _ = node.stop().await;
+        tx.send(res).unwrap();
+    })
+    .unwrap();
+
+    match rx.recv().unwrap() {
+        Ok(res) => res.unwrap(),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
 }
@@ -1,15 +1,84 @@
-use crate::{
-    atomic::{self, ArcBool},
-    WorkerPair,
+use crate::{FrameOptions, KeepaliveOptions, TcpError, TcpPeer, WorkerPair};
+use ockam::{async_worker, Address, Context, Result, RouterMessage, StopReason, Worker};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
-use ockam::{async_worker, Address, Context, Result, RouterMessage, Worker};
-use std::net::SocketAddr;
-use tokio::net::TcpListener;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+
+/// The options of a TCP listener
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenerOptions {
+    /// The number of simultaneous incoming connections, or `None` for
+    /// no limit
+    ///
+    /// Connections beyond this limit are closed right after they
+    /// were accepted.  Connections count towards the limit from the
+    /// moment they are accepted, even while their handshake is still
+    /// in progress.
+    pub max_connections: Option<usize>,
+    /// The limits for the frames exchanged on incoming connections
+    pub framing: FrameOptions,
+    /// The heartbeat and idle timeout settings of incoming connections
+    pub keepalive: KeepaliveOptions,
+}
+
+/// Counts a connection for as long as its receiving worker is alive
+pub(crate) struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(count))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A handle to a TCP listener
+///
+/// Dropping this handle keeps the listener running.
+pub struct TcpListenerHandle {
+    addr: Address,
+    local_addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+}
+
+impl TcpListenerHandle {
+    /// Return the address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Return the number of open incoming connections
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
 
+    /// Stop accepting connections and unbind the listener
+    ///
+    /// Connections that were already accepted stay open.
+    pub async fn close(self, ctx: &Context) -> Result<()> {
+        ctx.stop_worker(self.addr).await
+    }
+}
+
+/// A worker owning a bound TCP listener
+///
+/// Connections are accepted by a separate task, which is stopped when
+/// this worker shuts down.
 pub struct TcpListenWorker {
-    inner: TcpListener,
-    run: ArcBool,
-    router_addr: Address,
+    stop: Option<oneshot::Sender<()>>,
 }
 
 impl TcpListenWorker {
@@ -17,20 +86,36 @@ impl TcpListenWorker {
         ctx: &Context,
         router_addr: Address,
         addr: SocketAddr,
-        run: ArcBool,
-    ) -> Result<()> {
-        let waddr = format!("{}_listener", addr);
-
+        opts: ListenerOptions,
+    ) -> Result<TcpListenerHandle> {
         debug!("Binding TcpListener to {}", addr);
-        let inner = TcpListener::bind(addr).await.unwrap();
-        let worker = Self {
+        let inner = TcpListener::bind(addr).await.map_err(|e| {
+            error!("Failed to bind TcpListener to {}: {}", addr, e);
+            TcpError::BindFailed
+        })?;
+        let local_addr = inner.local_addr().map_err(TcpError::from)?;
+        let waddr = Address::from(format!("{}_listener", local_addr));
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let (stop, stopped) = oneshot::channel();
+        let accept = Accept {
+            ctx: ctx.new_context(format!("{}_accept", waddr)).await?,
             inner,
-            run,
             router_addr,
+            opts,
+            connections: Arc::clone(&connections),
+            accepted: 0,
         };
 
-        ctx.start_worker(waddr.as_str(), worker).await?;
-        Ok(())
+        ctx.start_worker(waddr.clone(), Self { stop: Some(stop) })
+            .await?;
+        tokio::spawn(accept.run(stopped));
+
+        Ok(TcpListenerHandle {
+            addr: waddr,
+            local_addr,
+            connections,
+        })
     }
 }
 
@@ -41,28 +126,183 @@ impl Worker for TcpListenWorker {
     // Do not actually listen for messages
     type Message = ();
 
-    async fn initialize(&mut self, ctx: &mut Self::Context) -> Result<()> {
-        // FIXME: see ArcBool future note
-        while atomic::check(&self.run) {
+    async fn shutdown(&mut self, _: &mut Context, _: StopReason) -> Result<()> {
+        // The accept task drops the listener once it has stopped
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        Ok(())
+    }
+}
+
+/// The accept loop of a listener
+struct Accept {
+    ctx: Context,
+    inner: TcpListener,
+    router_addr: Address,
+    opts: ListenerOptions,
+    connections: Arc<AtomicUsize>,
+    /// The number of accepted connections, to name their contexts
+    accepted: usize,
+}
+
+impl Accept {
+    async fn run(mut self, mut stopped: oneshot::Receiver<()>) {
+        loop {
             trace!("Waiting for incoming TCP connection...");
 
-            // Wait for an incoming connection
-            let (stream, peer) = self.inner.accept().await.unwrap();
+            // Wait for an incoming connection, or for the listener to
+            // be stopped.  The listening worker going away without
+            // sending a stop signal also stops this loop.
+            let (stream, peer) = tokio::select! {
+                _ = &mut stopped => break,
+                res = self.inner.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept TCP connection: {}", e);
+                        continue;
+                    }
+                },
+            };
 
-            // And spawn a connection worker for it
-            let pair = WorkerPair::with_stream(ctx, stream, peer).await?;
+            let open = self.connections.load(Ordering::Relaxed);
+            if self.opts.max_connections.map_or(false, |max| open >= max) {
+                warn!(
+                    "Rejecting connection from {}: {} connections are open",
+                    peer, open
+                );
+                continue;
+            }
 
-            // Register the connection with the local TcpRouter
-            ctx.send_message(
+            // Spawn a connection worker for it, unless the peer turns
+            // out to be incompatible.  The handshake happens in the
+            // background, so that a silent peer can't hold up other
+            // connections, but the connection counts towards the limit
+            // right away.
+            let slot = ConnectionSlot::take(&self.connections);
+            self.accepted += 1;
+            let ctx = match self
+                .ctx
+                .new_context(format!("{}_{}", self.ctx.primary_address(), self.accepted))
+                .await
+            {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    warn!("Failed to accept connection from {}: {}", peer, e);
+                    continue;
+                }
+            };
+            tokio::spawn(Self::connect(
+                ctx,
                 self.router_addr.clone(),
-                RouterMessage::Register {
-                    accepts: format!("1#{}", peer).into(),
-                    self_addr: pair.tx_addr.clone(),
-                },
-            )
-            .await?;
+                stream,
+                TcpPeer::from(peer),
+                self.opts,
+                slot,
+            ));
+        }
+
+        debug!("Stopped accepting TCP connections");
+    }
+
+    /// Start a worker pair for an accepted connection, and register it
+    /// with the router
+    async fn connect(
+        ctx: Context,
+        router_addr: Address,
+        stream: TcpStream,
+        peer: TcpPeer,
+        opts: ListenerOptions,
+        slot: ConnectionSlot,
+    ) {
+        let pair = match WorkerPair::with_stream(
+            &ctx,
+            stream,
+            peer.clone(),
+            None,
+            opts.framing,
+            opts.keepalive,
+            Some(slot),
+        )
+        .await
+        {
+            Ok(pair) => pair,
+            Err(e) => {
+                debug!("Failed to accept connection from {}: {}", peer, e);
+                return;
+            }
+        };
+
+        // Register the connection with the local TcpRouter
+        let register = RouterMessage::Register {
+            accepts: peer.address(),
+            self_addr: pair.tx_addr.clone(),
+        };
+        if let Err(e) = ctx.send_message(router_addr, register).await {
+            warn!("Failed to register connection from {}: {}", peer, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_node, ListenerOptions, TcpTransport};
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::{net::TcpStream, time};
+
+    async fn wait_for_connections(count: usize, connections: impl Fn() -> usize) {
+        let mut waited = 0;
+        while connections() != count {
+            assert!(waited < 100, "expected {} connections", count);
+            time::sleep(Duration::from_millis(50)).await;
+            waited += 1;
         }
+    }
+
+    #[test]
+    fn silent_peers_dont_block_accepts() {
+        test_node(|ctx| async move {
+            let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let listener = TcpTransport::listen(&ctx, addr, ListenerOptions::default()).await?;
+
+            // This peer never sends its preamble
+            let _silent = TcpStream::connect(listener.local_addr()).await.unwrap();
+            wait_for_connections(1, || listener.connections()).await;
+
+            // Well below the handshake timeout
+            let pair = time::timeout(
+                Duration::from_secs(2),
+                TcpTransport::create(&ctx, listener.local_addr()),
+            )
+            .await
+            .expect("a silent peer must not block other connections")?;
+            wait_for_connections(2, || listener.connections()).await;
+
+            time::timeout(Duration::from_secs(2), listener.close(&ctx))
+                .await
+                .expect("a silent peer must not block closing the listener")?;
+            pair.stop(&ctx).await
+        })
+    }
+
+    #[test]
+    fn handshakes_count_towards_limit() {
+        test_node(|ctx| async move {
+            let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let opts = ListenerOptions {
+                max_connections: Some(1),
+                ..Default::default()
+            };
+            let listener = TcpTransport::listen(&ctx, addr, opts).await?;
+
+            let _silent = TcpStream::connect(listener.local_addr()).await.unwrap();
+            wait_for_connections(1, || listener.connections()).await;
 
-        ctx.stop_worker(ctx.primary_address()).await
+            // The connection is closed before its handshake
+            let res = TcpTransport::create(&ctx, listener.local_addr()).await;
+            assert!(res.is_err());
+            assert_eq!(listener.connections(), 1);
+            Ok(())
+        })
     }
 }
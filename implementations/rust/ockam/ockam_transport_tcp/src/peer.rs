@@ -0,0 +1,170 @@
+use crate::{TcpError, TCP};
+use ockam::Address;
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// The address of a remote TCP peer
+///
+/// A peer is either an IP address or a host name, together with a
+/// port.  Host names are resolved whenever a connection to the peer
+/// is established, which means that they can be used in `type = 1`
+/// addresses, such as `1#hub.example.com:4000`.
+///
+/// Converting a string with `From` panics if the string is not a
+/// valid peer address.  Use [`str::parse`] for peers that aren't
+/// known to be valid.
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct TcpPeer {
+    host: String,
+    port: u16,
+}
+
+impl TcpPeer {
+    /// Create a peer address from a host name or IP address and a port
+    pub fn new<S: Into<String>>(host: S, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+
+    /// Return the host name or IP address of this peer
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Return the port of this peer
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Return the socket address of this peer, if its host is an IP address
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.host
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, self.port))
+    }
+
+    /// Return the `type = 1` address of this peer
+    pub fn address(&self) -> Address {
+        format!("{}#{}", TCP, self).into()
+    }
+}
+
+impl Display for TcpPeer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // IPv6 addresses need brackets to separate them from the port
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl From<SocketAddr> for TcpPeer {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+impl FromStr for TcpPeer {
+    type Err = TcpError;
+
+    fn from_str(s: &str) -> Result<Self, TcpError> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+
+        let sep = s.rfind(':').ok_or(TcpError::InvalidPeer)?;
+        let (host, port) = (&s[..sep], &s[sep + 1..]);
+        if host.is_empty() || host.contains(':') {
+            return Err(TcpError::InvalidPeer);
+        }
+        let port = port.parse().map_err(|_| TcpError::InvalidPeer)?;
+
+        Ok(Self::new(host, port))
+    }
+}
+
+impl<'a> From<&'a str> for TcpPeer {
+    fn from(s: &'a str) -> Self {
+        match s.parse() {
+            Ok(peer) => peer,
+            Err(e) => panic!("Invalid TCP peer address '{}': {:?}", s, e),
+        }
+    }
+}
+
+impl From<String> for TcpPeer {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_peers() {
+        let table = [
+            ("127.0.0.1:4000", "127.0.0.1", 4000, "127.0.0.1:4000"),
+            (
+                "hub.example.com:4000",
+                "hub.example.com",
+                4000,
+                "hub.example.com:4000",
+            ),
+            ("localhost:0", "localhost", 0, "localhost:0"),
+            ("[::1]:4000", "::1", 4000, "[::1]:4000"),
+            ("[fe80::1]:65535", "fe80::1", 65535, "[fe80::1]:65535"),
+        ];
+
+        for &(s, host, port, display) in table.iter() {
+            let peer: TcpPeer = s.parse().unwrap();
+            assert_eq!((peer.host(), peer.port()), (host, port), "{}", s);
+            assert_eq!(peer.to_string(), display);
+            assert_eq!(peer.to_string().parse::<TcpPeer>().unwrap(), peer);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_peers() {
+        let table = [
+            // Missing port
+            "127.0.0.1",
+            "hub.example.com",
+            "hub.example.com:",
+            "[::1]",
+            // Missing host
+            ":4000",
+            // IPv6 addresses need brackets
+            "::1:4000",
+            // Invalid port
+            "hub.example.com:port",
+            "hub.example.com:65536",
+            "",
+        ];
+
+        for s in table.iter() {
+            assert!(
+                matches!(s.parse::<TcpPeer>(), Err(TcpError::InvalidPeer)),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn peer_addresses() {
+        let peer: TcpPeer = "[::1]:4000".parse().unwrap();
+        assert_eq!(peer.socket_addr(), Some("[::1]:4000".parse().unwrap()));
+        assert_eq!(peer.address(), Address::from_string("1#[::1]:4000"));
+        assert_eq!(TcpPeer::new("hub.example.com", 4000).socket_addr(), None);
+    }
+}
@@ -1,9 +1,17 @@
 use crate::{
     atomic::{self, ArcBool},
-    TcpError,
+    connection::{ConnectionState, SharedConnection, CONNECTION_EVENTS},
+    framing, handshake,
+    listener::ConnectionSlot,
+    resolver,
+    router::DEFAULT_ADDRESS,
+    Capabilities, FrameOptions, KeepaliveOptions, ReconnectPolicy, TcpConnectionEvent, TcpPeer,
+    BYTES_RECEIVED,
 };
-use ockam::{async_worker, Address, Context, Result, TransportMessage, Worker};
-use tokio::{io::AsyncReadExt, net::tcp::OwnedReadHalf};
+use ockam::{
+    async_worker, Address, Context, Result, RouterMessage, StopReason, TransportMessage, Worker,
+};
+use tokio::{io::AsyncWriteExt, net::tcp::OwnedReadHalf, sync::oneshot, time};
 
 /// A TCP receiving message worker
 ///
@@ -11,12 +19,14 @@ use tokio::{io::AsyncReadExt, net::tcp::OwnedReadHalf};
 /// [`start_tcp_worker`](crate::start_tcp_worker)!
 ///
 /// This half of the worker is created when spawning a new connection
-/// worker pair, and listens for incoming TCP packets, to relay into
-/// the node message system.
+/// worker pair.  Incoming TCP packets are read by a separate task,
+/// which relays them into the node message system, and which is
+/// stopped when this worker shuts down.  For outbound connections the
+/// task is also responsible for reconnecting when the connection is
+/// lost.
 pub struct TcpRecvWorker {
-    pub(crate) rx: OwnedReadHalf,
     pub(crate) run: ArcBool,
-    pub(crate) peer_addr: Address,
+    pub(crate) stop: Option<oneshot::Sender<()>>,
 }
 
 #[async_worker]
@@ -26,46 +36,249 @@ impl Worker for TcpRecvWorker {
     // Do not actually listen for messages
     type Message = ();
 
-    // We are using the initialize function here to run a custom loop,
-    // while never listening for messages sent to our address
-    //
-    // Note: when the loop exits, we _must_ call stop_worker(..) on
-    // Context not to spawn a zombie task.
-    //
-    // Also: we must stop the TcpReceive loop when the worker gets
-    // killed by the user or node.
-    async fn initialize(&mut self, ctx: &mut Context) -> Result<()> {
-        let self_addr = ctx.primary_address();
+    async fn shutdown(&mut self, _: &mut Context, _: StopReason) -> Result<()> {
+        // Stop sending heartbeats, and interrupt the receiving task
+        // even if it is waiting for the peer
+        atomic::stop(&self.run);
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        Ok(())
+    }
+}
 
-        // Run in a loop until TcpWorkerPair::stop() is called
-        // FIXME: see ArcBool future note
-        while atomic::check(&self.run) {
-            // First read a message length header...
-            let len = match self.rx.read_u16().await {
-                Ok(len) => len,
+/// The receive loop of a connection
+pub(crate) struct Receive {
+    pub(crate) ctx: Context,
+    pub(crate) rx: OwnedReadHalf,
+    pub(crate) run: ArcBool,
+    pub(crate) peer: TcpPeer,
+    pub(crate) peer_addr: Address,
+    pub(crate) rx_addr: Address,
+    pub(crate) tx_addr: Address,
+    pub(crate) conn: SharedConnection,
+    pub(crate) policy: Option<ReconnectPolicy>,
+    /// The limits for incoming frames, which are announced to the peer
+    pub(crate) framing: FrameOptions,
+    pub(crate) keepalive: KeepaliveOptions,
+    /// Whether the peer sends heartbeats
+    pub(crate) heartbeats: bool,
+    /// Counts this connection towards the limit of its listener
+    pub(crate) _slot: Option<ConnectionSlot>,
+}
+
+impl Receive {
+    async fn notify(&self, state: ConnectionState) {
+        let event = TcpConnectionEvent {
+            peer: self.peer_addr.clone(),
+            state,
+        };
+        if let Err(e) = self.ctx.publish(CONNECTION_EVENTS, event).await {
+            debug!("Failed to publish TCP connection event: {}", e);
+        }
+    }
+
+    /// Remove the sending worker from the router, and stop it
+    async fn teardown(&self) {
+        self.notify(ConnectionState::Failed).await;
+        let deregister = RouterMessage::Deregister {
+            accepts: self.peer_addr.clone(),
+            self_addr: self.tx_addr.clone(),
+        };
+        if let Err(e) = self.ctx.send_message(DEFAULT_ADDRESS, deregister).await {
+            debug!("Failed to deregister TCP peer {}: {}", self.peer, e);
+        }
+        if let Err(e) = self
+            .ctx
+            .stop_worker_with_reason(self.tx_addr.clone(), StopReason::PeerLost)
+            .await
+        {
+            debug!("Failed to stop sending worker {}: {}", self.tx_addr, e);
+        }
+    }
+
+    /// Re-establish a lost outbound connection
+    ///
+    /// Returns `false` if all attempts failed, or if the worker pair
+    /// was stopped in the meantime.
+    async fn reconnect(
+        &mut self,
+        policy: ReconnectPolicy,
+        stopped: &mut oneshot::Receiver<()>,
+    ) -> bool {
+        // Make the sending worker buffer messages from now on
+        self.conn.lock().await.tx = None;
+
+        let mut attempt = 0;
+        while let Some(delay) = policy.delay(attempt) {
+            attempt += 1;
+            self.notify(ConnectionState::Reconnecting { attempt }).await;
+            tokio::select! {
+                _ = &mut *stopped => return false,
+                _ = time::sleep(delay) => {}
+            }
+
+            let mut stream = match resolver::connect(&self.peer).await {
+                Ok(stream) => stream,
                 Err(e) => {
-                    error!("Failed to receive message: {}", e);
+                    debug!("Failed to reconnect to {}: {}", self.peer, e);
+                    continue;
+                }
+            };
+            let protocol = match handshake::handshake(&mut stream, &self.peer, &self.framing).await
+            {
+                Ok(protocol) => protocol,
+                Err(_) => continue,
+            };
+            let (rx, mut tx) = stream.into_split();
+
+            // Send the messages buffered while reconnecting, before
+            // handing the connection back to the sending worker
+            let mut conn = self.conn.lock().await;
+            let mut flushed = true;
+            while let Some(frame) = conn.buffer.pop_front() {
+                if tx.write_all(&frame).await.is_err() {
+                    conn.buffer.push_front(frame);
+                    flushed = false;
                     break;
                 }
+            }
+            if !flushed {
+                continue;
+            }
+
+            // The peer may have been upgraded in the meantime
+            self.heartbeats = protocol.capabilities.contains(Capabilities::HEARTBEATS);
+            conn.heartbeats = self.heartbeats;
+            conn.framing = protocol.framing;
+            conn.tx = Some(tx);
+            self.rx = rx;
+            return true;
+        }
+
+        false
+    }
+
+    /// Relay incoming messages until the connection is lost for good,
+    /// or until the receiving worker is stopped
+    ///
+    /// The receiving worker going away without sending a stop signal
+    /// also stops this loop.  When the loop ends on its own, it tears
+    /// the whole worker pair down, so that no zombie worker is left
+    /// behind.
+    pub(crate) async fn run(mut self, mut stopped: oneshot::Receiver<()>) {
+        match self.receive(&mut stopped).await {
+            // The receiving worker is already shutting down
+            Ok(None) => {}
+            Ok(Some(reason)) => {
+                if let Err(e) = self
+                    .ctx
+                    .stop_worker_with_reason(self.rx_addr.clone(), reason)
+                    .await
+                {
+                    debug!("Failed to stop receiving worker {}: {}", self.rx_addr, e);
+                }
+            }
+            Err(e) => {
+                error!("Failed to receive from TCP peer {}: {}", self.peer, e);
+                self.teardown().await;
+                if let Err(e) = self
+                    .ctx
+                    .stop_worker_with_reason(self.rx_addr.clone(), StopReason::Error)
+                    .await
+                {
+                    debug!("Failed to stop receiving worker {}: {}", self.rx_addr, e);
+                }
+            }
+        }
+
+        // Close the connection, even though the heartbeat task may
+        // still hold on to it until its next tick, and drop the
+        // counters of the peer
+        self.conn.lock().await.tx = None;
+        self.ctx.metrics().remove("peer", &self.peer.to_string());
+        debug!("Stopped receiving from TCP peer {}", self.peer);
+    }
+
+    /// Run the receive loop
+    ///
+    /// Returns the reason to stop the receiving worker with, or `None`
+    /// if it is being stopped already.
+    async fn receive(&mut self, stopped: &mut oneshot::Receiver<()>) -> Result<Option<StopReason>> {
+        let peer = self.peer.to_string();
+        self.notify(ConnectionState::Connected).await;
+
+        // Run in a loop until TcpWorkerPair::stop() is called
+        while atomic::check(&self.run) {
+            // Connections are only closed for being idle if the peer
+            // keeps them alive with heartbeats
+            let idle_timeout = if self.heartbeats {
+                self.keepalive.idle_timeout
+            } else {
+                None
             };
 
-            trace!("Received message header for {} bytes", len);
+            // Read all frames of the next message.  Frames that
+            // exceed the limits leave the stream in an unknown state,
+            // so they are handled like a lost connection.
+            let next = tokio::select! {
+                _ = &mut *stopped => return Ok(None),
+                next = framing::read_message(&mut self.rx, &self.framing, idle_timeout) => next,
+            };
+            let (buf, read) = match next {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Failed to receive message: {:?}", e);
+                    self.notify(ConnectionState::Disconnected).await;
+                    if !atomic::check(&self.run) {
+                        return Ok(None);
+                    }
 
-            // Allocate a buffer of that size
-            let mut buf = vec![0; len as usize];
+                    // Outbound connections are re-established, and
+                    // registered with the router again.  All others
+                    // are torn down.
+                    let reconnected = match self.policy {
+                        Some(policy) => self.reconnect(policy, stopped).await,
+                        None => false,
+                    };
+                    if !atomic::check(&self.run) {
+                        return Ok(None);
+                    }
+                    if !reconnected {
+                        self.teardown().await;
+                        return Ok(Some(StopReason::PeerLost));
+                    }
 
-            // Then Read into the buffer
-            match self.rx.read_exact(&mut buf).await {
-                Ok(_) => {}
-                _ => {
-                    error!("Failed to receive message of length: {}", len);
+                    info!("Reconnected to TCP peer {}", self.peer);
+                    self.ctx
+                        .send_message(
+                            DEFAULT_ADDRESS,
+                            RouterMessage::Register {
+                                accepts: self.peer_addr.clone(),
+                                self_addr: self.tx_addr.clone(),
+                            },
+                        )
+                        .await?;
+                    self.notify(ConnectionState::Connected).await;
                     continue;
                 }
-            }
+            };
 
-            // Deserialize the message now
-            let mut msg: TransportMessage =
-                serde_bare::from_slice(buf.as_slice()).map_err(|_| TcpError::RecvBadMessage)?;
+            // Count the frame headers together with the message
+            self.ctx
+                .metrics()
+                .increment(BYTES_RECEIVED, &[("peer", peer.as_str())], read as u64);
+
+            // Deserialize the message now.  A message that can't be
+            // decoded or delivered only affects itself, so it is
+            // dropped without closing the connection.
+            let mut msg: TransportMessage = match serde_bare::from_slice(buf.as_slice()) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    warn!("Dropping malformed message from TCP peer {}", self.peer);
+                    continue;
+                }
+            };
 
             // Insert the peer address into the return route so that
             // reply routing can be properly resolved
@@ -82,11 +295,71 @@ impl Worker for TcpRecvWorker {
             // Forward the message to the final destination worker,
             // which consumes the TransportMessage and yields the
             // final message type
-            ctx.forward_message(msg).await?;
+            let onward = msg.onward_route.clone();
+            if let Err(e) = self.ctx.forward_message(msg).await {
+                warn!(
+                    "Dropping message from TCP peer {} to {}: {}",
+                    self.peer, onward, e
+                );
+            }
         }
 
-        // Stop the worker to not fall into the next read loop
-        ctx.stop_worker(self_addr).await?;
-        Ok(())
+        // The run flag is only cleared while the worker pair is
+        // being stopped
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{framing, handshake, test_node, FrameOptions, ListenerOptions, TcpTransport};
+    use ockam::{Address, Message, Route, TransportMessage};
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::{io::AsyncWriteExt, net::TcpStream, time};
+
+    fn frame(msg: TransportMessage) -> Vec<u8> {
+        let buf = serde_bare::to_vec(&msg).unwrap();
+        framing::encode(&buf, &FrameOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn drop_undeliverable_messages() {
+        test_node(|mut ctx| async move {
+            let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let listener = TcpTransport::listen(&ctx, addr, ListenerOptions::default()).await?;
+            let mut stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+            let framing = FrameOptions::default();
+            handshake::handshake(&mut stream, &listener.local_addr().into(), &framing).await?;
+
+            // A malformed message, and one for a worker that doesn't exist
+            let garbage = framing::encode(&[0xff], &FrameOptions::default())?;
+            stream.write_all(&garbage).await.unwrap();
+            let lost = TransportMessage::v1(Route::from("nobody"), "lost".to_string().encode()?);
+            stream.write_all(&frame(lost)).await.unwrap();
+
+            // Neither closes the connection
+            let found = TransportMessage::v1(
+                Route::from(ctx.primary_address()),
+                "found".to_string().encode()?,
+            );
+            stream.write_all(&frame(found)).await.unwrap();
+            let msg = ctx
+                .receive_timeout::<String>(Duration::from_secs(5))
+                .await?;
+            assert_eq!(msg.take().take(), "found");
+            assert_eq!(listener.connections(), 1);
+
+            // Closing the socket tears down both workers of the pair
+            let peer = stream.local_addr().unwrap();
+            drop(stream);
+            let tx_addr = Address::from(format!("{}_tx", peer));
+            let mut waited = 0;
+            while ctx.list_workers().await?.contains(&tx_addr) {
+                assert!(waited < 100, "sending worker was not stopped");
+                time::sleep(Duration::from_millis(50)).await;
+                waited += 1;
+            }
+            Ok(())
+        })
     }
 }
@@ -0,0 +1,103 @@
+//! Host name resolution for TCP peers
+//!
+//! Resolved addresses are cached for all connections of a process.
+//! A cache entry is dropped once it has expired, or once connecting
+//! to all of its addresses failed, so that a peer that moved to a
+//! different address is found again on the next connection attempt.
+
+use crate::{TcpError, TcpPeer};
+use lazy_static::lazy_static;
+use ockam::Result;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::net::{lookup_host, TcpStream};
+
+/// How long resolved addresses are reused
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+struct Entry {
+    addrs: Vec<SocketAddr>,
+    resolved: Instant,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<BTreeMap<TcpPeer, Entry>> = Mutex::new(BTreeMap::new());
+}
+
+/// Return the cached addresses of a peer, if they haven't expired
+fn cached(peer: &TcpPeer) -> Option<Vec<SocketAddr>> {
+    let mut cache = CACHE.lock().unwrap();
+    match cache.get(peer) {
+        Some(entry) if entry.resolved.elapsed() < CACHE_TTL => Some(entry.addrs.clone()),
+        Some(_) => {
+            cache.remove(peer);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Resolve a peer to its socket addresses
+pub(crate) async fn resolve(peer: &TcpPeer) -> Result<Vec<SocketAddr>> {
+    if let Some(addr) = peer.socket_addr() {
+        return Ok(vec![addr]);
+    }
+    if let Some(addrs) = cached(peer) {
+        return Ok(addrs);
+    }
+
+    debug!("Resolving TCP peer {}", peer);
+    let addrs: Vec<_> = lookup_host((peer.host(), peer.port()))
+        .await
+        .map_err(|e| {
+            warn!("Failed to resolve TCP peer {}: {}", peer, e);
+            TcpError::ResolveFailed
+        })?
+        .collect();
+    if addrs.is_empty() {
+        return Err(TcpError::ResolveFailed.into());
+    }
+
+    CACHE.lock().unwrap().insert(
+        peer.clone(),
+        Entry {
+            addrs: addrs.clone(),
+            resolved: Instant::now(),
+        },
+    );
+    Ok(addrs)
+}
+
+/// Forget the cached addresses of a peer
+pub(crate) fn invalidate(peer: &TcpPeer) {
+    CACHE.lock().unwrap().remove(peer);
+}
+
+/// Connect to a peer, trying each of its addresses in turn
+///
+/// If none of the cached addresses of a peer accept the connection,
+/// its host name is resolved again before giving up.
+pub(crate) async fn connect(peer: &TcpPeer) -> Result<TcpStream> {
+    let mut last_err = TcpError::PeerNotFound;
+    let was_cached = peer.socket_addr().is_none() && cached(peer).is_some();
+    let attempts = if was_cached { 2 } else { 1 };
+
+    for _ in 0..attempts {
+        for addr in resolve(peer).await? {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("Failed to connect to {} at {}: {}", peer, addr, e);
+                    last_err = e.into();
+                }
+            }
+        }
+        invalidate(peer);
+    }
+
+    Err(last_err.into())
+}
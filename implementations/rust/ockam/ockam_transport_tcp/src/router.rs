@@ -1,24 +1,57 @@
 use crate::{
-    atomic::{self, ArcBool},
-    listener::TcpListenWorker,
-    WorkerPair,
+    listener::{ListenerOptions, TcpListenWorker, TcpListenerHandle},
+    ConnectionOptions, TcpPeer, WorkerPair, TCP, TCP_SCHEME,
 };
-use ockam::{async_worker, Address, Context, Result, Routed, RouterMessage, Worker};
-use std::{collections::BTreeMap, net::SocketAddr};
+use ockam::{
+    async_worker, register_scheme, Address, Context, Error, Result, RouteError, Routed,
+    RouterMessage, TransportMessage, Worker,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+pub(crate) const DEFAULT_ADDRESS: &str = "io.ockam.router.tcp";
 
-const DEFAULT_ADDRESS: &'static str = "io.ockam.router.tcp";
+/// Messages waiting for a connection to their peer, per peer address
+type Pending = Arc<Mutex<BTreeMap<Address, Vec<TransportMessage>>>>;
+
+/// The reply to a message that could not be routed to its TCP peer
+///
+/// When the router fails to connect to a peer, every message that was
+/// waiting for this connection is answered with a `TcpRouteFailure`,
+/// which is sent along the return route of the message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TcpRouteFailure {
+    /// The address of the peer that could not be reached
+    pub peer: Address,
+    /// The code of the error that caused the failure
+    pub code: u32,
+}
 
 /// A TCP address router and connection listener
 ///
 /// In order to create new TCP connection workers you need a router to
 /// map remote addresses of `type = 1` to worker addresses.  This type
-/// facilitates this.
+/// facilitates this.  Peers are identified by the [`TcpPeer`] they
+/// were connected with, which may be a host name that is resolved
+/// when connecting.
+///
+/// [`TcpPeer`]: crate::TcpPeer
+///
+/// Messages to peers without a connection cause the router to
+/// connect to them.  These messages are queued until the connection
+/// is established, and answered with a [`TcpRouteFailure`] if it
+/// can't be.
 ///
 /// Optionally you can also start listening for incoming connections
 /// if the local node is part of a server architecture.
 pub struct TcpRouter {
     map: BTreeMap<Address, Address>,
-    run: ArcBool,
+    pending: Pending,
+    dials: usize,
 }
 
 /// A handle to connect to a TcpRouter
@@ -32,7 +65,7 @@ pub struct TcpRouterHandle<'c> {
 impl<'c> TcpRouterHandle<'c> {
     /// Register a new connection worker with this router
     pub async fn register(&self, pair: &WorkerPair) -> Result<()> {
-        let accepts = format!("1#{}", pair.peer.clone()).into();
+        let accepts = pair.peer.address();
         let self_addr = pair.tx_addr.clone();
 
         self.ctx
@@ -42,6 +75,18 @@ impl<'c> TcpRouterHandle<'c> {
             )
             .await
     }
+
+    /// Bind a new connection listener for this router
+    ///
+    /// A router can have any number of listeners, each of which can
+    /// be closed with the returned handle.
+    pub async fn listen<S: Into<SocketAddr>>(
+        &self,
+        socket_addr: S,
+        opts: ListenerOptions,
+    ) -> Result<TcpListenerHandle> {
+        TcpListenWorker::start(self.ctx, self.addr.clone(), socket_addr.into(), opts).await
+    }
 }
 
 #[async_worker]
@@ -50,8 +95,8 @@ impl Worker for TcpRouter {
     type Message = RouterMessage;
 
     async fn initialize(&mut self, ctx: &mut Context) -> Result<()> {
-        trace!("Registering TCP router for type = 1");
-        ctx.register(1, ctx.primary_address()).await?;
+        trace!("Registering TCP router for type = {}", TCP);
+        ctx.register(TCP, ctx.primary_address()).await?;
         Ok(())
     }
 
@@ -67,47 +112,156 @@ impl Worker for TcpRouter {
                 trace!("TCP route request: {:?}", msg.onward_route.next());
 
                 // Get the next hop
-                let onward = msg.onward_route.step().unwrap();
-
-                // Look up the connection worker responsible
-                let next = self.map.get(&onward).unwrap();
+                let onward = msg.onward_route.step().ok_or(RouteError::EmptyRoute)?;
 
-                // Modify the transport message route
-                msg.onward_route.modify().prepend(next.clone());
-
-                // Send the transport message to the connection worker
-                ctx.send_message(next.clone(), msg).await?;
+                // Look up the connection worker responsible, or
+                // connect to the peer if there isn't one yet
+                match self.map.get(&onward).cloned() {
+                    Some(next) => {
+                        // A worker pair that failed to reconnect has
+                        // stopped, in which case a new one is needed
+                        if let Err(e) = Self::forward(ctx, &next, msg.clone()).await {
+                            debug!("TCP connection worker {} is gone: {}", next, e);
+                            self.map.remove(&onward);
+                            self.queue(ctx, onward, msg).await?;
+                        }
+                    }
+                    None => self.queue(ctx, onward, msg).await?,
+                }
             }
             Register { accepts, self_addr } => {
                 trace!("TCP registration request: {} => {}", accepts, self_addr);
+
+                // Deliver the messages that waited for this connection.
+                // A message that fails doesn't affect the others, nor
+                // the registration.
+                let queued = self.pending.lock().unwrap().remove(&accepts);
+                for msg in queued.unwrap_or_default() {
+                    if let Err(e) = Self::forward(ctx, &self_addr, msg).await {
+                        warn!("Failed to forward queued message to {}: {}", accepts, e);
+                    }
+                }
                 self.map.insert(accepts, self_addr);
             }
+            Deregister { accepts, self_addr } => {
+                trace!("TCP deregistration request: {} => {}", accepts, self_addr);
+
+                // A newer connection to the same peer may have
+                // replaced the worker in the meantime
+                if self.map.get(&accepts) == Some(&self_addr) {
+                    self.map.remove(&accepts);
+                }
+            }
         };
 
         Ok(())
     }
+}
 
-    fn shutdown(&mut self, _: &mut Context) -> Result<()> {
-        // Shut down the ListeningWorker if it exists
-        atomic::stop(&self.run);
-        Ok(())
+/// Connect to a peer and register the new connection with the router
+///
+/// If the connection fails, all messages queued for the peer are
+/// answered with a [`TcpRouteFailure`] instead.
+async fn dial(ctx: Context, router: Address, onward: Address, peer: TcpPeer, pending: Pending) {
+    let e = match WorkerPair::start(&ctx, peer.clone(), ConnectionOptions::default()).await {
+        Ok(pair) => {
+            let register = RouterMessage::Register {
+                accepts: onward.clone(),
+                self_addr: pair.tx_addr.clone(),
+            };
+            match ctx.send_message(router, register).await {
+                Ok(()) => return,
+                Err(e) => e,
+            }
+        }
+        Err(e) => e,
+    };
+
+    warn!("Failed to connect to TCP peer {}: {}", peer, e);
+    let queued = pending.lock().unwrap().remove(&onward);
+    for msg in queued.unwrap_or_default() {
+        let failure = TcpRouteFailure {
+            peer: onward.clone(),
+            code: e.code(),
+        };
+        if let Err(e) = ctx.send_message(msg.return_route, failure).await {
+            debug!("Failed to return TCP routing failure: {}", e);
+        }
     }
 }
 
 impl TcpRouter {
-    async fn start(ctx: &Context, waddr: &Address, run: Option<ArcBool>) -> Result<()> {
+    /// Send a transport message to the connection worker for its peer
+    async fn forward(ctx: &Context, next: &Address, mut msg: TransportMessage) -> Result<()> {
+        // Modify the transport message route
+        msg.onward_route.modify().prepend(next.clone());
+
+        // Send the transport message to the connection worker
+        ctx.send_message(next.clone(), msg).await
+    }
+
+    /// Queue a message for a peer without a connection
+    ///
+    /// The first message queued for a peer starts a connection
+    /// attempt, which happens in the background so that the router
+    /// can keep routing messages to other peers.
+    async fn queue(&mut self, ctx: &Context, onward: Address, msg: TransportMessage) -> Result<()> {
+        if let Some(queued) = self.pending.lock().unwrap().get_mut(&onward) {
+            queued.push(msg);
+            return Ok(());
+        }
+
+        let peer = match String::from_utf8_lossy(&onward).parse::<TcpPeer>() {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("Dropping message to invalid TCP address {}", onward);
+                let failure = TcpRouteFailure {
+                    peer: onward,
+                    code: Error::from(e).code(),
+                };
+                return ctx.send_message(msg.return_route, failure).await;
+            }
+        };
+
+        debug!("Connecting to TCP peer {} on demand", peer);
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(onward.clone(), vec![msg]);
+
+        // The connection is established with a separate context, so
+        // that it can register itself with this router once it's done
+        self.dials += 1;
+        let dial_ctx = ctx
+            .new_context(format!("{}_dial_{}", ctx.primary_address(), self.dials))
+            .await?;
+        tokio::spawn(dial(
+            dial_ctx,
+            ctx.primary_address(),
+            onward,
+            peer,
+            Arc::clone(&self.pending),
+        ));
+        Ok(())
+    }
+
+    async fn start(ctx: &Context, waddr: &Address) -> Result<()> {
         debug!("Initialising new TcpRouter with address {}", waddr);
+        if let Err(e) = register_scheme(TCP_SCHEME, TCP) {
+            warn!("Failed to register TCP address scheme: {:?}", e);
+        }
 
         let router = Self {
             map: BTreeMap::new(),
-            run: run.unwrap_or_else(|| atomic::new(true)),
+            pending: Arc::new(Mutex::new(BTreeMap::new())),
+            dials: 0,
         };
         ctx.start_worker(waddr.clone(), router).await?;
         Ok(())
     }
 
     /// Either register a new router or return a handle to the existing one
-    pub(crate) async fn register_or_get<'c>(ctx: &'c Context) -> Result<TcpRouterHandle<'c>> {
+    pub(crate) async fn register_or_get(ctx: &Context) -> Result<TcpRouterHandle<'_>> {
         let addr = Address::from(DEFAULT_ADDRESS);
         Self::register(ctx).await.or_else(|_| {
             debug!("Using pre-existing TCP router...");
@@ -119,9 +273,9 @@ impl TcpRouter {
     ///
     /// To also handle incoming connections, use
     /// [`TcpRouter::bind`](TcpRouter::bind)
-    pub async fn register<'c>(ctx: &'c Context) -> Result<TcpRouterHandle<'c>> {
+    pub async fn register(ctx: &Context) -> Result<TcpRouterHandle<'_>> {
         let addr = Address::from(DEFAULT_ADDRESS);
-        Self::start(ctx, &addr, None).await?;
+        Self::start(ctx, &addr).await?;
         Ok(TcpRouterHandle { ctx, addr })
     }
 
@@ -130,18 +284,17 @@ impl TcpRouter {
     /// Use this function when your node is the server part of your
     /// connection architecture.  For clients that shouldn't listen
     /// for connections themselves, use
-    /// [`TcpRouter::register`](TcpRouter::register).
-    pub async fn bind<'c, S: Into<SocketAddr>>(
-        ctx: &'c Context,
+    /// [`TcpRouter::register`](TcpRouter::register).  To close the
+    /// listener again, or to configure it, bind it with
+    /// [`TcpRouterHandle::listen`](TcpRouterHandle::listen) instead.
+    pub async fn bind<S: Into<SocketAddr>>(
+        ctx: &Context,
         socket_addr: S,
-    ) -> Result<TcpRouterHandle<'c>> {
-        let run = atomic::new(true);
-        let addr = Address::from(DEFAULT_ADDRESS);
-
-        // Bind and start the connection listen worker
-        TcpListenWorker::start(ctx, addr.clone(), socket_addr.into(), run.clone()).await?;
-
-        Self::start(ctx, &addr, Some(run)).await?;
-        Ok(TcpRouterHandle { ctx, addr })
+    ) -> Result<TcpRouterHandle<'_>> {
+        let router = Self::register(ctx).await?;
+        router
+            .listen(socket_addr, ListenerOptions::default())
+            .await?;
+        Ok(router)
     }
 }
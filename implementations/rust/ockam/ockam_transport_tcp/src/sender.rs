@@ -1,7 +1,11 @@
-use crate::TcpError;
-use ockam::{async_worker, Context, Result, Routed, TransportMessage, Worker};
-use std::net::SocketAddr;
-use tokio::{io::AsyncWriteExt, net::tcp::OwnedWriteHalf};
+use crate::{
+    connection::SharedConnection, framing, router::DEFAULT_ADDRESS, FrameOptions, TcpError,
+    TcpPeer, BYTES_SENT,
+};
+use ockam::{
+    async_worker, Context, Result, Routed, RouterMessage, StopReason, TransportMessage, Worker,
+};
+use tokio::io::AsyncWriteExt;
 
 /// A TCP sending message worker
 ///
@@ -10,29 +14,19 @@ use tokio::{io::AsyncWriteExt, net::tcp::OwnedWriteHalf};
 ///
 /// This half of the worker is created when spawning a new connection
 /// worker pair, and listens for messages from the node message system
-/// to dispatch to a remote peer.
+/// to dispatch to a remote peer.  While an outbound connection is
+/// re-established, messages are buffered instead.  Messages that can't
+/// be sent or buffered fail with a [`TcpError`].
 pub struct TcpSendWorker {
-    pub(crate) tx: OwnedWriteHalf,
-    pub(crate) peer: SocketAddr,
+    pub(crate) conn: SharedConnection,
+    pub(crate) peer: TcpPeer,
 }
 
-fn prepare_message(msg: TransportMessage) -> Result<Vec<u8>> {
-    let mut msg_buf = serde_bare::to_vec(&msg).map_err(|_| TcpError::SendBadMessage)?;
+fn prepare_message(msg: TransportMessage, opts: &FrameOptions) -> Result<Vec<u8>> {
+    let msg_buf = serde_bare::to_vec(&msg).map_err(|_| TcpError::SendBadMessage)?;
 
-    // Create a buffer that includes the message length in big endian
-    let mut len = (msg_buf.len() as u16).to_be_bytes().to_vec();
-
-    // Fun fact: reversing a vector in place, appending the length,
-    // and then reversing it again is faster for large message sizes
-    // than adding the large chunk of data.
-    //
-    // https://play.rust-lang.org/?version=stable&mode=release&edition=2018&gist=8669a640004ac85c7be38b19e3e73dcb
-    msg_buf.reverse();
-    len.reverse();
-    msg_buf.append(&mut len);
-    msg_buf.reverse();
-
-    Ok(msg_buf)
+    // Split the message into frames, each prefixed with its length
+    Ok(framing::encode(&msg_buf, opts)?)
 }
 
 #[async_worker]
@@ -51,14 +45,66 @@ impl Worker for TcpSendWorker {
         // knows what to do with the incoming message
         msg.onward_route.step();
 
-        // Create a message buffer with pre-pended length
-        let msg = prepare_message(msg.take())?;
+        // Continue the trace of the message that was sent to us
+        let mut msg = msg.take();
+        msg.trace = ctx.trace().or(msg.trace);
+
+        // Create a message buffer split into frames, within the
+        // limits negotiated with the peer
+        let mut conn = self.conn.lock().await;
+        let msg = prepare_message(msg, &conn.framing)?;
+        let written = match conn.tx.as_mut() {
+            Some(tx) => tx.write_all(msg.as_slice()).await,
+            // The connection is being re-established, or is gone
+            None => {
+                return match conn.buffer(msg) {
+                    Ok(()) => Ok(()),
+                    Err(e) => self.reject(ctx, e).await,
+                }
+            }
+        };
+
+        match written {
+            Ok(()) => {
+                let peer = self.peer.to_string();
+                ctx.metrics()
+                    .increment(BYTES_SENT, &[("peer", peer.as_str())], msg.len() as u64);
+            }
+            Err(_) => {
+                warn!("Failed to send message to peer {}", self.peer);
 
-        if let Err(_) = self.tx.write(msg.as_slice()).await {
-            warn!("Failed to send message to peer {}", self.peer);
-            ctx.stop_worker(ctx.primary_address()).await?;
+                // Outbound connections keep the message until the
+                // receiving worker has reconnected
+                conn.tx = None;
+                if let Err(e) = conn.buffer(msg) {
+                    return self.reject(ctx, e).await;
+                }
+            }
         }
 
         Ok(())
     }
 }
+
+impl TcpSendWorker {
+    /// Fail a message that can't be sent or buffered
+    ///
+    /// A connection that is never re-established is removed from the
+    /// router, and this worker stops, so that later messages to the
+    /// peer don't disappear in a dead worker.
+    async fn reject(&self, ctx: &Context, e: TcpError) -> Result<()> {
+        warn!("Dropping message to TCP peer {}: {:?}", self.peer, e);
+        if let TcpError::ConnectionDrop = e {
+            let deregister = RouterMessage::Deregister {
+                accepts: self.peer.address(),
+                self_addr: ctx.primary_address(),
+            };
+            if let Err(e) = ctx.send_message(DEFAULT_ADDRESS, deregister).await {
+                debug!("Failed to deregister TCP peer {}: {}", self.peer, e);
+            }
+            ctx.stop_worker_with_reason(ctx.primary_address(), StopReason::PeerLost)
+                .await?;
+        }
+        Err(e.into())
+    }
+}
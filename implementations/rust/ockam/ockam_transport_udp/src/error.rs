@@ -0,0 +1,39 @@
+use ockam::Error;
+
+/// A UDP transport specific error type
+#[derive(Clone, Copy, Debug)]
+pub enum UdpError {
+    /// Failed to send a malformed message
+    SendBadMessage,
+    /// Failed to receive a malformed message
+    RecvBadMessage,
+    /// Failed to bind to the desired socket
+    BindFailed,
+    /// A peer address could not be parsed
+    InvalidPeer,
+    /// A message exceeds the maximum message size
+    MessageTooLarge,
+    /// The MTU leaves no room for the payload of a fragment
+    InvalidMtu,
+    /// A generic I/O failure
+    GenericIo,
+}
+
+impl UdpError {
+    /// Integer code associated with the error domain.
+    pub const DOMAIN_CODE: u32 = 18_000;
+    /// Error domain
+    pub const DOMAIN_NAME: &'static str = "OCKAM_TRANSPORT_UDP";
+}
+
+impl From<UdpError> for Error {
+    fn from(e: UdpError) -> Error {
+        Error::new(UdpError::DOMAIN_CODE + (e as u32), UdpError::DOMAIN_NAME)
+    }
+}
+
+impl From<std::io::Error> for UdpError {
+    fn from(_: std::io::Error) -> Self {
+        Self::GenericIo
+    }
+}
@@ -0,0 +1,428 @@
+//! Message framing on UDP datagrams
+//!
+//! Every datagram carries one fragment of a message.  A fragment
+//! starts with the framing version, followed by the id of its message,
+//! its index within the message and the number of fragments in the
+//! message, all in big endian.  Messages that fit into a single
+//! datagram are sent as a message with one fragment.
+//!
+//! ```text
+//! +---------+----------------+-----------+-----------+-----------------+
+//! | version | message id u32 | index u16 | count u16 | payload ...     |
+//! +---------+----------------+-----------+-----------+-----------------+
+//! ```
+//!
+//! The receiver collects the fragments of a message until all of them
+//! have arrived, in any order.  Messages with missing fragments are
+//! dropped after the reassembly timeout, since lost datagrams are
+//! never sent again.
+
+use crate::UdpError;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// The size of a fragment header
+pub(crate) const HEADER_LEN: usize = 9;
+
+/// The framing version spoken by this implementation
+const VERSION: u8 = 1;
+
+/// The largest datagram that can be received
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// The options of a UDP transport
+///
+/// Both ends should use the same message size limit, since messages
+/// that exceed it are dropped by the receiver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UdpOptions {
+    /// The largest datagram to send, including the fragment header
+    ///
+    /// The default fits into the minimum IPv6 MTU, so that datagrams
+    /// are not fragmented by the IP layer.
+    pub mtu: usize,
+    /// The largest message that can be sent or received
+    pub max_message_size: usize,
+    /// How long to wait for the missing fragments of a message
+    pub reassembly_timeout: Duration,
+    /// The number of partially received messages to keep
+    ///
+    /// Fragments of further messages are dropped until one of the
+    /// pending messages is complete or timed out.
+    pub max_pending: usize,
+}
+
+impl Default for UdpOptions {
+    fn default() -> Self {
+        Self {
+            mtu: 1232,
+            max_message_size: 1024 * 1024,
+            reassembly_timeout: Duration::from_secs(5),
+            max_pending: 64,
+        }
+    }
+}
+
+/// Split a message into datagrams that each fit into the MTU
+pub(crate) fn fragment(id: u32, msg: &[u8], opts: &UdpOptions) -> Result<Vec<Vec<u8>>, UdpError> {
+    if msg.len() > opts.max_message_size {
+        return Err(UdpError::MessageTooLarge);
+    }
+    if opts.mtu <= HEADER_LEN {
+        return Err(UdpError::InvalidMtu);
+    }
+
+    // Empty messages are sent as a single empty fragment
+    let chunk_size = opts.mtu.min(MAX_DATAGRAM_SIZE) - HEADER_LEN;
+    let count = ((msg.len() + chunk_size - 1) / chunk_size).max(1);
+    let count = u16::try_from(count).map_err(|_| UdpError::MessageTooLarge)?;
+
+    let mut datagrams = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let start = index as usize * chunk_size;
+        let end = msg.len().min(start + chunk_size);
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + end - start);
+        buf.push(VERSION);
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&index.to_be_bytes());
+        buf.extend_from_slice(&count.to_be_bytes());
+        buf.extend_from_slice(&msg[start..end]);
+        datagrams.push(buf);
+    }
+    Ok(datagrams)
+}
+
+/// The header of a received fragment
+struct Header {
+    id: u32,
+    index: u16,
+    count: u16,
+}
+
+/// Split a datagram into its fragment header and payload
+fn parse(datagram: &[u8]) -> Option<(Header, &[u8])> {
+    if datagram.len() < HEADER_LEN || datagram[0] != VERSION {
+        return None;
+    }
+
+    let header = Header {
+        id: u32::from_be_bytes([datagram[1], datagram[2], datagram[3], datagram[4]]),
+        index: u16::from_be_bytes([datagram[5], datagram[6]]),
+        count: u16::from_be_bytes([datagram[7], datagram[8]]),
+    };
+    if header.count == 0 || header.index >= header.count {
+        return None;
+    }
+    Some((header, &datagram[HEADER_LEN..]))
+}
+
+/// The fragments of a message that has not been fully received yet
+///
+/// Only the fragments that were received are stored, so that the
+/// fragment count of a datagram doesn't decide how much memory is
+/// allocated.
+struct Partial {
+    fragments: BTreeMap<u16, Vec<u8>>,
+    count: u16,
+    size: usize,
+    started: Instant,
+}
+
+/// Collects fragments until their message is complete
+///
+/// Message ids are only unique per sending peer, so pending messages
+/// are kept apart by the address they were received from.
+pub(crate) struct Reassembler {
+    pending: HashMap<(SocketAddr, u32), Partial>,
+    opts: UdpOptions,
+}
+
+impl Reassembler {
+    pub(crate) fn new(opts: UdpOptions) -> Self {
+        Self {
+            pending: HashMap::new(),
+            opts,
+        }
+    }
+
+    /// Add a received datagram
+    ///
+    /// Returns the message it completes, if any.  Malformed datagrams,
+    /// duplicate fragments and messages that exceed the limits are
+    /// dropped.
+    pub(crate) fn insert(&mut self, peer: SocketAddr, datagram: &[u8]) -> Option<Vec<u8>> {
+        self.expire();
+
+        let (header, payload) = match parse(datagram) {
+            Some(parsed) => parsed,
+            None => {
+                debug!("Dropping malformed datagram from {}", peer);
+                return None;
+            }
+        };
+
+        // Most messages fit into a single datagram
+        if header.count == 1 {
+            if payload.len() > self.opts.max_message_size {
+                warn!("Dropping message from {}: message too large", peer);
+                return None;
+            }
+            return Some(payload.to_vec());
+        }
+
+        // Fragments of a message with more than one fragment are
+        // never empty, so a message has at most one fragment per byte
+        if payload.is_empty() || header.count as usize > self.opts.max_message_size {
+            debug!("Dropping malformed fragment from {}", peer);
+            return None;
+        }
+
+        let key = (peer, header.id);
+        if !self.pending.contains_key(&key) && self.pending.len() >= self.opts.max_pending {
+            warn!("Dropping fragment from {}: too many pending messages", peer);
+            return None;
+        }
+        let partial = self.pending.entry(key).or_insert_with(|| Partial {
+            fragments: BTreeMap::new(),
+            count: header.count,
+            size: 0,
+            started: Instant::now(),
+        });
+
+        // A message id may be reused by a peer that restarted
+        if partial.count != header.count {
+            debug!("Dropping inconsistent fragments from {}", peer);
+            self.pending.remove(&key);
+            return None;
+        }
+        if partial.fragments.contains_key(&header.index) {
+            trace!("Ignoring duplicate fragment from {}", peer);
+            return None;
+        }
+        if partial.size + payload.len() > self.opts.max_message_size {
+            warn!("Dropping message from {}: message too large", peer);
+            self.pending.remove(&key);
+            return None;
+        }
+
+        partial.fragments.insert(header.index, payload.to_vec());
+        partial.size += payload.len();
+        if partial.fragments.len() < partial.count as usize {
+            return None;
+        }
+
+        let partial = self.pending.remove(&key)?;
+        let mut msg = Vec::with_capacity(partial.size);
+        for fragment in partial.fragments.values() {
+            msg.extend_from_slice(fragment);
+        }
+        Some(msg)
+    }
+
+    /// Drop the messages whose fragments took too long to arrive
+    fn expire(&mut self) {
+        let timeout = self.opts.reassembly_timeout;
+        self.pending.retain(|(peer, _), partial| {
+            let alive = partial.started.elapsed() < timeout;
+            if !alive {
+                debug!("Dropping incomplete message from {}", peer);
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    /// Options that split messages into fragments of four bytes
+    fn small() -> UdpOptions {
+        UdpOptions {
+            mtu: HEADER_LEN + 4,
+            max_message_size: 64,
+            ..UdpOptions::default()
+        }
+    }
+
+    #[test]
+    fn single_datagram_round_trip() {
+        let opts = UdpOptions::default();
+        let mut reassembler = Reassembler::new(opts);
+
+        for msg in &[&b"hello"[..], &[]] {
+            let datagrams = fragment(7, msg, &opts).unwrap();
+            assert_eq!(datagrams.len(), 1);
+            assert_eq!(datagrams[0].len(), HEADER_LEN + msg.len());
+            assert_eq!(
+                reassembler.insert(peer(), &datagrams[0]).as_deref(),
+                Some(*msg)
+            );
+        }
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn out_of_order_fragments() {
+        let mut reassembler = Reassembler::new(small());
+        let msg = b"0123456789";
+        let datagrams = fragment(1, msg, &small()).unwrap();
+        assert_eq!(datagrams.len(), 3);
+
+        assert_eq!(reassembler.insert(peer(), &datagrams[2]), None);
+        assert_eq!(reassembler.insert(peer(), &datagrams[0]), None);
+        assert_eq!(
+            reassembler.insert(peer(), &datagrams[1]).as_deref(),
+            Some(&msg[..])
+        );
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn fragments_of_different_peers() {
+        let mut reassembler = Reassembler::new(small());
+        let other: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let datagrams = fragment(1, b"01234567", &small()).unwrap();
+
+        assert_eq!(reassembler.insert(peer(), &datagrams[0]), None);
+        assert_eq!(reassembler.insert(other, &datagrams[1]), None);
+        assert_eq!(reassembler.pending.len(), 2);
+    }
+
+    #[test]
+    fn ignores_duplicate_fragments() {
+        let mut reassembler = Reassembler::new(small());
+        let msg = b"01234567";
+        let datagrams = fragment(1, msg, &small()).unwrap();
+
+        assert_eq!(reassembler.insert(peer(), &datagrams[0]), None);
+        assert_eq!(reassembler.insert(peer(), &datagrams[0]), None);
+        assert_eq!(
+            reassembler.insert(peer(), &datagrams[1]).as_deref(),
+            Some(&msg[..])
+        );
+    }
+
+    #[test]
+    fn drops_mismatched_counts() {
+        let mut reassembler = Reassembler::new(small());
+        let first = fragment(1, b"0123456789", &small()).unwrap();
+        let second = fragment(1, b"01234567", &small()).unwrap();
+
+        assert_eq!(reassembler.insert(peer(), &first[0]), None);
+        assert_eq!(reassembler.insert(peer(), &second[1]), None);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn drops_malformed_datagrams() {
+        let mut reassembler = Reassembler::new(small());
+        let mut datagram = fragment(1, b"0123456789", &small()).unwrap().remove(0);
+
+        assert_eq!(
+            reassembler.insert(peer(), &datagram[..HEADER_LEN - 1]),
+            None
+        );
+
+        // Index out of range
+        datagram[5..7].copy_from_slice(&3u16.to_be_bytes());
+        assert_eq!(reassembler.insert(peer(), &datagram), None);
+
+        // Empty fragment of a larger message
+        datagram[5..7].copy_from_slice(&0u16.to_be_bytes());
+        assert_eq!(reassembler.insert(peer(), &datagram[..HEADER_LEN]), None);
+
+        // Unknown framing version
+        datagram[0] = VERSION + 1;
+        assert_eq!(reassembler.insert(peer(), &datagram), None);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn enforces_message_size_limit() {
+        assert!(matches!(
+            fragment(1, &[0; 65], &small()),
+            Err(UdpError::MessageTooLarge)
+        ));
+
+        // Messages from peers with a larger limit are dropped
+        let opts = UdpOptions {
+            max_message_size: 8,
+            ..small()
+        };
+        let mut reassembler = Reassembler::new(opts);
+        let datagrams = fragment(1, b"0123456789", &small()).unwrap();
+        assert_eq!(reassembler.insert(peer(), &datagrams[0]), None);
+        assert_eq!(reassembler.insert(peer(), &datagrams[1]), None);
+        assert_eq!(reassembler.insert(peer(), &datagrams[2]), None);
+        assert!(reassembler.pending.is_empty());
+
+        let datagrams = fragment(2, b"0123456789", &UdpOptions::default()).unwrap();
+        assert_eq!(reassembler.insert(peer(), &datagrams[0]), None);
+    }
+
+    #[test]
+    fn rejects_counts_beyond_size_limit() {
+        let mut reassembler = Reassembler::new(small());
+        let mut datagram = fragment(1, b"0123456789", &small()).unwrap().remove(0);
+
+        // Every fragment carries at least one byte, so a message of
+        // at most 64 bytes can't have more than 64 fragments
+        datagram[7..9].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(reassembler.insert(peer(), &datagram), None);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn limits_pending_messages() {
+        let opts = UdpOptions {
+            max_pending: 1,
+            ..small()
+        };
+        let mut reassembler = Reassembler::new(opts);
+        let first = fragment(1, b"01234567", &opts).unwrap();
+        let second = fragment(2, b"abcdefgh", &opts).unwrap();
+
+        assert_eq!(reassembler.insert(peer(), &first[0]), None);
+        assert_eq!(reassembler.insert(peer(), &second[0]), None);
+        assert_eq!(reassembler.pending.len(), 1);
+
+        // Completing a message makes room for the next one
+        assert!(reassembler.insert(peer(), &first[1]).is_some());
+        assert_eq!(reassembler.insert(peer(), &second[0]), None);
+        assert_eq!(
+            reassembler.insert(peer(), &second[1]).as_deref(),
+            Some(&b"abcdefgh"[..])
+        );
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let opts = UdpOptions {
+            reassembly_timeout: Duration::from_millis(20),
+            ..small()
+        };
+        let mut reassembler = Reassembler::new(opts);
+        let datagrams = fragment(1, b"01234567", &opts).unwrap();
+
+        assert_eq!(reassembler.insert(peer(), &datagrams[0]), None);
+        std::thread::sleep(Duration::from_millis(40));
+
+        // The first fragment is gone, so the message stays incomplete
+        assert_eq!(reassembler.insert(peer(), &datagrams[1]), None);
+        assert_eq!(reassembler.pending.len(), 1);
+
+        std::thread::sleep(Duration::from_millis(40));
+        reassembler.expire();
+        assert!(reassembler.pending.is_empty());
+    }
+}
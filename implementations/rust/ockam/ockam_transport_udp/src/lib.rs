@@ -0,0 +1,102 @@
+//! UDP Transport utilities for Ockam's routing framework
+//!
+//! The `ockam_node` (or `ockam_node_no_std`) crate sits at the core
+//! of the Ockam routing framework, with transport specific
+//! abstraction plugins.  This crate implements a UDP plugin for this
+//! architecture.
+//!
+//! UDP delivery is unreliable: messages may be lost, duplicated or
+//! reordered, and nothing is sent again.  Messages that don't fit into
+//! a single datagram are split into fragments, which are reassembled
+//! by the receiving node.  Peers are addressed by their socket
+//! address, as in `2#127.0.0.1:4000` or `udp://127.0.0.1:4000`.
+
+#![deny(
+    // missing_docs,
+    trivial_casts,
+    trivial_numeric_casts,
+    unsafe_code,
+    unused_import_braces,
+    unused_qualifications,
+)]
+
+#[macro_use]
+extern crate tracing;
+
+mod error;
+mod framing;
+mod receiver;
+mod router;
+
+pub use error::UdpError;
+pub use framing::UdpOptions;
+pub use router::{UdpRouteFailure, UdpRouter, UdpRouterHandle};
+
+use ockam::{Address, Context, Result};
+use std::net::SocketAddr;
+
+/// The address type of UDP peers
+pub const UDP: u8 = 2;
+
+/// The scheme of UDP peer addresses, as in `udp://127.0.0.1:4000`
+pub const UDP_SCHEME: &str = "udp://";
+
+/// The metric counting bytes sent over UDP
+///
+/// Peers are not tracked by the transport, so there is no per-peer
+/// counter that could ever be removed again.
+pub const BYTES_SENT: &str = "ockam_udp_bytes_sent_total";
+
+/// The metric counting bytes received over UDP
+pub const BYTES_RECEIVED: &str = "ockam_udp_bytes_received_total";
+
+/// Return the address of a UDP peer
+pub fn udp_address(peer: SocketAddr) -> Address {
+    format!("{}#{}", UDP, peer).into()
+}
+
+/// An API layer object to control Ockam UDP transports
+pub struct UdpTransport;
+
+impl UdpTransport {
+    /// Create a UDP transport bound to a local socket address
+    ///
+    /// Bind to port `0` to let the operating system pick a free port,
+    /// which [`UdpRouterHandle::local_addr`] returns.  A node can only
+    /// have one UDP transport.
+    pub async fn create<S: Into<SocketAddr>>(ctx: &Context, addr: S) -> Result<UdpRouterHandle> {
+        UdpRouter::start(ctx, addr.into(), UdpOptions::default()).await
+    }
+
+    /// Create a UDP transport with custom options
+    pub async fn create_with_options<S: Into<SocketAddr>>(
+        ctx: &Context,
+        addr: S,
+        opts: UdpOptions,
+    ) -> Result<UdpRouterHandle> {
+        UdpRouter::start(ctx, addr.into(), opts).await
+    }
+}
+
+/// Run a test on a fresh node, and stop the node once it is done
+#[cfg(test)]
+pub(crate) fn test_node<F, Fut>(test: F)
+where
+    F: FnOnce(Context) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+{
+    let (ctx, mut exe) = ockam::start_node();
+    let (tx, rx) = std::sync::mpsc::channel();
+    exe.execute(async move {
+        let node = ctx.new_context("test_node").await.unwrap();
+        let res = tokio::spawn(test(ctx)).await;
+        let _ = node.stop().await;
+        tx.send(res).unwrap();
+    })
+    .unwrap();
+
+    match rx.recv().unwrap() {
+        Ok(res) => res.unwrap(),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
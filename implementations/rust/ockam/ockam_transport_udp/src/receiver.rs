@@ -0,0 +1,78 @@
+use crate::{
+    framing::{Reassembler, MAX_DATAGRAM_SIZE},
+    udp_address, UdpOptions, BYTES_RECEIVED,
+};
+use ockam::{Context, TransportMessage};
+use std::sync::Arc;
+use tokio::{net::UdpSocket, sync::oneshot};
+
+/// The receive loop of a UDP router
+///
+/// Datagrams are reassembled into messages, which are forwarded to
+/// their destination worker on this node.
+pub(crate) struct Receive {
+    ctx: Context,
+    socket: Arc<UdpSocket>,
+    reassembler: Reassembler,
+}
+
+impl Receive {
+    pub(crate) fn new(ctx: Context, socket: Arc<UdpSocket>, opts: UdpOptions) -> Self {
+        Self {
+            ctx,
+            socket,
+            reassembler: Reassembler::new(opts),
+        }
+    }
+
+    pub(crate) async fn run(mut self, mut stopped: oneshot::Receiver<()>) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            // Wait for a datagram, or for the router to be stopped.
+            // The router going away without sending a stop signal
+            // also stops this loop.
+            let (len, peer) = tokio::select! {
+                _ = &mut stopped => break,
+                res = self.socket.recv_from(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!("Failed to receive UDP datagram: {}", e);
+                        continue;
+                    }
+                },
+            };
+
+            self.ctx
+                .metrics()
+                .increment(BYTES_RECEIVED, &[], len as u64);
+
+            let msg_buf = match self.reassembler.insert(peer, &buf[..len]) {
+                Some(msg_buf) => msg_buf,
+                None => continue,
+            };
+
+            // Deserialize the message now
+            let mut msg: TransportMessage = match serde_bare::from_slice(msg_buf.as_slice()) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    warn!("Dropping malformed message from UDP peer {}", peer);
+                    continue;
+                }
+            };
+
+            // Insert the peer address into the return route so that
+            // reply routing can be properly resolved
+            msg.return_route.modify().prepend(udp_address(peer));
+            trace!("Message onward route: {}", msg.onward_route);
+            trace!("Message return route: {}", msg.return_route);
+
+            // A message for a worker that doesn't exist is lost, like
+            // any other datagram
+            if let Err(e) = self.ctx.forward_message(msg).await {
+                debug!("Failed to forward message from UDP peer {}: {}", peer, e);
+            }
+        }
+
+        debug!("Stopped receiving UDP datagrams");
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{framing, receiver::Receive, UdpError, UdpOptions, BYTES_SENT, UDP, UDP_SCHEME};
+use ockam::{
+    async_worker, register_scheme, Address, Context, Error, Result, RouteError, Routed,
+    RouterMessage, StopReason, TransportMessage, Worker,
+};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{net::UdpSocket, sync::oneshot};
+
+pub(crate) const DEFAULT_ADDRESS: &str = "io.ockam.router.udp";
+
+/// The reply to a message that could not be routed to its UDP peer
+///
+/// Since UDP delivery is unreliable, this is only sent for messages
+/// whose peer address is invalid, and never for lost messages.  It is
+/// sent along the return route of the message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UdpRouteFailure {
+    /// The address of the peer that could not be reached
+    pub peer: Address,
+    /// The code of the error that caused the failure
+    pub code: u32,
+}
+
+/// A UDP address router
+///
+/// The router owns the UDP socket of a node, and sends messages for
+/// addresses of `type = 2` to the peer named by the address, such as
+/// `2#127.0.0.1:4000`.  There are no connections, and no worker per
+/// peer: every message is split into datagrams and sent right away.
+///
+/// Datagrams received on the socket are reassembled into messages by
+/// a separate task, which is stopped when the router shuts down.
+/// Their return route starts with the address of the sending peer, so
+/// that replies are routed back over UDP.
+pub struct UdpRouter {
+    socket: Arc<UdpSocket>,
+    opts: UdpOptions,
+    next_id: u32,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+/// A handle to a running UdpRouter
+///
+/// Dropping this handle keeps the router running.
+pub struct UdpRouterHandle {
+    addr: Address,
+    local_addr: SocketAddr,
+}
+
+impl UdpRouterHandle {
+    /// Return the address the UDP socket is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop the router and close its socket
+    ///
+    /// The router is deregistered first, so that a new UDP transport
+    /// can be created on the same node.
+    pub async fn stop(self, ctx: &Context) -> Result<()> {
+        ctx.deregister(UDP, self.addr.clone()).await?;
+        ctx.stop_worker(self.addr).await
+    }
+}
+
+impl UdpRouter {
+    /// Bind a UDP socket and start a router for it
+    pub(crate) async fn start(
+        ctx: &Context,
+        addr: SocketAddr,
+        opts: UdpOptions,
+    ) -> Result<UdpRouterHandle> {
+        if let Err(e) = register_scheme(UDP_SCHEME, UDP) {
+            warn!("Failed to register UDP address scheme: {:?}", e);
+        }
+
+        debug!("Binding UdpSocket to {}", addr);
+        let socket = UdpSocket::bind(addr).await.map_err(|e| {
+            error!("Failed to bind UdpSocket to {}: {}", addr, e);
+            UdpError::BindFailed
+        })?;
+        let local_addr = socket.local_addr().map_err(UdpError::from)?;
+        let socket = Arc::new(socket);
+
+        let (stop, stopped) = oneshot::channel();
+        let router = Self {
+            socket: Arc::clone(&socket),
+            opts,
+            next_id: 0,
+            stop: Some(stop),
+        };
+        ctx.start_worker(DEFAULT_ADDRESS, router).await?;
+
+        // Register the router right away, so that messages can be
+        // sent over UDP as soon as this function returns
+        trace!("Registering UDP router for type = {}", UDP);
+        ctx.register(UDP, DEFAULT_ADDRESS).await?;
+
+        let receive = Receive::new(
+            ctx.new_context(format!("{}_recv", DEFAULT_ADDRESS)).await?,
+            socket,
+            opts,
+        );
+        tokio::spawn(receive.run(stopped));
+
+        Ok(UdpRouterHandle {
+            addr: DEFAULT_ADDRESS.into(),
+            local_addr,
+        })
+    }
+
+    /// Split a message into datagrams and send them to a peer
+    async fn send(&mut self, ctx: &Context, peer: SocketAddr, msg: TransportMessage) -> Result<()> {
+        let msg_buf = serde_bare::to_vec(&msg).map_err(|_| UdpError::SendBadMessage)?;
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        // Once a datagram fails to send, the message can't be
+        // reassembled anymore, so the rest is not sent
+        let mut sent = 0;
+        for datagram in framing::fragment(id, &msg_buf, &self.opts)? {
+            match self.socket.send_to(&datagram, peer).await {
+                Ok(len) => sent += len,
+                Err(e) => {
+                    warn!("Failed to send datagram to UDP peer {}: {}", peer, e);
+                    break;
+                }
+            }
+        }
+
+        ctx.metrics().increment(BYTES_SENT, &[], sent as u64);
+        Ok(())
+    }
+}
+
+#[async_worker]
+impl Worker for UdpRouter {
+    type Context = Context;
+    type Message = RouterMessage;
+
+    async fn shutdown(&mut self, _: &mut Context, _: StopReason) -> Result<()> {
+        // The receiving task drops the socket once it has stopped
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        Ok(())
+    }
+
+    async fn handle_message(
+        &mut self,
+        ctx: &mut Context,
+        msg: Routed<RouterMessage>,
+    ) -> Result<()> {
+        let msg = msg.take();
+        use RouterMessage::*;
+        match msg {
+            Route(mut msg) => {
+                trace!("UDP route request: {:?}", msg.onward_route.next());
+
+                // Remove the peer address from the route so the other
+                // end knows what to do with the incoming message
+                let onward = msg.onward_route.step().ok_or(RouteError::EmptyRoute)?;
+                let peer = match String::from_utf8_lossy(&onward).parse::<SocketAddr>() {
+                    Ok(peer) => peer,
+                    Err(_) => {
+                        warn!("Dropping message to invalid UDP address {}", onward);
+                        let failure = UdpRouteFailure {
+                            peer: onward,
+                            code: Error::from(UdpError::InvalidPeer).code(),
+                        };
+                        return ctx.send_message(msg.return_route, failure).await;
+                    }
+                };
+
+                // Continue the trace of the message that was sent to us
+                msg.trace = ctx.trace().or(msg.trace);
+                self.send(ctx, peer, msg).await?;
+            }
+            Register { accepts, .. } | Deregister { accepts, .. } => {
+                // Peers are addressed directly, so there is nothing
+                // to register
+                debug!("Ignoring UDP registration for {}", accepts);
+            }
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_node, udp_address, UdpError, UdpOptions, UdpRouteFailure, UdpTransport};
+    use ockam::{Error, Route};
+    use std::{net::SocketAddr, time::Duration};
+
+    fn local() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[test]
+    fn routes_fragmented_messages() {
+        test_node(|mut ctx| async move {
+            let opts = UdpOptions {
+                mtu: 64,
+                ..UdpOptions::default()
+            };
+            let udp = UdpTransport::create_with_options(&ctx, local(), opts).await?;
+            let peer = udp_address(udp.local_addr());
+
+            // Send a message to ourselves, across the UDP socket
+            let msg = "ping".repeat(100);
+            let route: Route = Route::new().append(peer.clone()).append("app").into();
+            ctx.send_message(route, msg.clone()).await?;
+
+            let reply = ctx
+                .receive_timeout::<String>(Duration::from_secs(5))
+                .await?
+                .take();
+            assert_eq!(reply.reply().next(), Some(&peer));
+            assert_eq!(reply.take(), msg);
+            udp.stop(&ctx).await
+        })
+    }
+
+    #[test]
+    fn reports_invalid_peers() {
+        test_node(|mut ctx| async move {
+            let udp = UdpTransport::create(&ctx, local()).await?;
+
+            let route: Route = Route::new().append("2#not-a-peer").append("app").into();
+            ctx.send_message(route, "ping".to_string()).await?;
+
+            let failure = ctx
+                .receive_timeout::<UdpRouteFailure>(Duration::from_secs(5))
+                .await?
+                .take()
+                .take();
+            assert_eq!(failure.peer, "2#not-a-peer".into());
+            assert_eq!(failure.code, Error::from(UdpError::InvalidPeer).code());
+            udp.stop(&ctx).await
+        })
+    }
+
+    #[test]
+    fn recreate_after_stop() {
+        test_node(|ctx| async move {
+            let udp = UdpTransport::create(&ctx, local()).await?;
+            udp.stop(&ctx).await?;
+
+            let udp = UdpTransport::create(&ctx, local()).await?;
+            assert!(ctx.node_info().await?.routers.contains_key(&crate::UDP));
+            udp.stop(&ctx).await?;
+            assert!(!ctx.node_info().await?.routers.contains_key(&crate::UDP));
+            Ok(())
+        })
+    }
+}
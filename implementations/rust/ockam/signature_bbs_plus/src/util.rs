@@ -11,7 +11,7 @@ use subtle::CtOption;
 
 macro_rules! slicer {
     ($d:expr, $b:expr, $e:expr, $s:expr) => {
-        &<[u8; $s]>::try_from(&$d[$b..$e]).unwrap();
+        &<[u8; $s]>::try_from(&$d[$b..$e]).unwrap()
     };
 }
 